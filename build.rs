@@ -0,0 +1,18 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use protobuf::Message;
+
+//compiles gtfs-realtime.proto into a descriptor set the server embeds for protojson and text output
+fn main() {
+    println!("cargo:rerun-if-changed=gtfs-realtime.proto");
+    let set = protobuf_parse::Parser::new()
+        .pure()
+        .include(".")
+        .input("gtfs-realtime.proto")
+        .file_descriptor_set()
+        .unwrap();
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("gtfs-realtime.desc");
+    fs::write(out, set.write_to_bytes().unwrap()).unwrap();
+}
@@ -0,0 +1,568 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader},
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{Duration as ChronoDuration, Utc};
+use redis::{Connection, RedisResult};
+
+//daily counters are kept a little past midnight so a late request doesn't start a new one
+const QUOTA_KEY_TTL_SECONDS: i64 = 2 * 24 * 60 * 60;
+//per feed usage is kept for a while for billing and abuse reports
+const USAGE_KEY_TTL_SECONDS: i64 = 35 * 24 * 60 * 60;
+//how often full buckets are dropped, anonymous clients would otherwise pile up
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+//how often requests counted in memory are written to redis
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+//one consumer allowed to use the server
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    pub key: String,
+    //shown in usage counters instead of the key itself
+    pub name: String,
+    //requests per second
+    pub rate: f64,
+    //requests that can be made at once before the rate applies
+    pub burst: f64,
+    //requests per day, counted in UTC, 0 for no limit
+    pub daily_quota: u64,
+    //space separated restricted feeds this key may use, * for all of them
+    #[serde(default)]
+    pub restricted_feeds: String,
+}
+
+impl ApiKey {
+    pub fn may_use(&self, feed: &str) -> bool {
+        self.restricted_feeds
+            .split_whitespace()
+            .any(|allowed| allowed == "*" || allowed == feed)
+    }
+}
+
+//loaded from a csv with key,name,rate,burst,daily_quota and optionally restricted_feeds
+pub fn load_api_keys(path: &str) -> anyhow::Result<HashMap<String, ApiKey>> {
+    let mut reader = csv::Reader::from_reader(BufReader::new(File::open(path)?));
+    let mut keys = HashMap::new();
+    for record in reader.deserialize::<ApiKey>() {
+        let record = record?;
+        keys.insert(record.key.clone(), record);
+    }
+    Ok(keys)
+}
+
+//feeds whose licence doesn't allow showing them to the public, one onestop id per line
+pub fn load_restricted_feeds(path: &str) -> anyhow::Result<HashSet<String>> {
+    let mut feeds = HashSet::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let feed = line.trim();
+        if !feed.is_empty() && !feed.starts_with('#') {
+            feeds.insert(feed.to_string());
+        }
+    }
+    Ok(feeds)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Denied {
+    MissingKey,
+    UnknownKey,
+    //how long until the next request is allowed
+    RateLimited(Duration),
+    QuotaExceeded(Duration),
+}
+
+impl Denied {
+    //stable code for the json error body
+    pub fn code(&self) -> &'static str {
+        match self {
+            Denied::MissingKey => "missing_api_key",
+            Denied::UnknownKey => "unknown_api_key",
+            Denied::RateLimited(_) => "rate_limited",
+            Denied::QuotaExceeded(_) => "quota_exceeded",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Denied::MissingKey => "an API key is required",
+            Denied::UnknownKey => "unknown API key",
+            Denied::RateLimited(_) => "rate limit exceeded",
+            Denied::QuotaExceeded(_) => "daily quota exceeded",
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Denied::RateLimited(wait) | Denied::QuotaExceeded(wait) => Some(*wait),
+            _ => None,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    //what the bucket was last checked with, so the sweep knows when it's full again
+    rate: f64,
+    burst: f64,
+}
+
+impl Bucket {
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate >= self.burst
+    }
+}
+
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    swept: Instant,
+}
+
+//token buckets in memory, so limits apply per server process
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> RateLimiter {
+        RateLimiter {
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn check(&self, id: &str, rate: f64, burst: f64) -> Result<(), Duration> {
+        let now = Instant::now();
+        let burst = burst.max(1.0);
+        let mut buckets = self.buckets.lock().unwrap();
+
+        //a full bucket is the same as no bucket, so those are dropped now and then
+        if now.duration_since(buckets.swept) >= SWEEP_INTERVAL {
+            buckets.buckets.retain(|_, bucket| !bucket.is_full(now));
+            buckets.swept = now;
+        }
+
+        let bucket = buckets.buckets.entry(id.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            rate,
+            burst,
+        });
+        bucket.tokens =
+            (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        bucket.rate = rate;
+        bucket.burst = burst;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if rate > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        } else {
+            Err(Duration::from_secs(60))
+        }
+    }
+}
+
+//requests counted in memory so no request waits on redis, written there by flush_usage
+#[derive(Default)]
+struct UsageCounts {
+    date: String,
+    //requests per key on date, everything in redis as of the last flush plus what's been counted since
+    used: HashMap<String, u64>,
+    //requests per date, key and feed not written to redis yet
+    pending: HashMap<(String, String, String), u64>,
+}
+
+impl UsageCounts {
+    //counts a request against the key's daily quota, false if the quota was already used up
+    fn count(&mut self, date: &str, name: &str, feed: Option<&str>, daily_quota: u64) -> bool {
+        if self.date != date {
+            self.date = date.to_string();
+            self.used.clear();
+        }
+        let used = self.used.entry(name.to_string()).or_insert(0);
+        if daily_quota > 0 && *used >= daily_quota {
+            return false;
+        }
+        *used += 1;
+        let key = (date.to_string(), name.to_string(), feed.unwrap_or("").to_string());
+        *self.pending.entry(key).or_insert(0) += 1;
+        true
+    }
+}
+
+//who may call the server, how often, and which feeds they get
+//requests without a key are let through unless require_key is set, limited per client address if anonymous limits are given
+//restricted feeds are only served to keys that list them
+#[derive(Default)]
+pub struct AccessControl {
+    keys: HashMap<String, ApiKey>,
+    restricted: HashSet<String>,
+    pub require_key: bool,
+    //rate and burst for requests without a key
+    pub anonymous: Option<(f64, f64)>,
+    //reverse proxies whose X-Forwarded-For and Forwarded headers are believed
+    trusted_proxies: HashSet<IpAddr>,
+    limiter: RateLimiter,
+    usage: Mutex<UsageCounts>,
+}
+
+impl AccessControl {
+    pub fn new(
+        keys: HashMap<String, ApiKey>,
+        restricted: HashSet<String>,
+        require_key: bool,
+        anonymous: Option<(f64, f64)>,
+        trusted_proxies: HashSet<IpAddr>,
+    ) -> AccessControl {
+        AccessControl {
+            keys,
+            restricted,
+            require_key,
+            anonymous,
+            trusted_proxies,
+            limiter: RateLimiter::default(),
+            usage: Mutex::new(UsageCounts::default()),
+        }
+    }
+
+    //the address anonymous limits apply to
+    //hops are the addresses the proxy headers list, oldest first, see forwarded_hops
+    //proxies append the address they got the request from, so anything left of the last trusted proxy may be made up
+    //the unix socket has no peer address and is only reachable by a local proxy, so it's treated as a trusted one
+    pub fn client_address(&self, peer: Option<IpAddr>, hops: &[String]) -> String {
+        let mut client = match peer {
+            Some(peer) if !self.trusted_proxies.contains(&peer) => return peer.to_string(),
+            Some(peer) => peer.to_string(),
+            None => String::from("unknown"),
+        };
+        for hop in hops.iter().rev() {
+            client = hop.clone();
+            match hop.parse::<IpAddr>() {
+                Ok(address) if self.trusted_proxies.contains(&address) => {}
+                _ => break,
+            }
+        }
+        client
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+            || !self.restricted.is_empty()
+            || self.require_key
+            || self.anonymous.is_some()
+    }
+
+    pub fn can_see(&self, feed: &str, api_key: Option<&ApiKey>) -> bool {
+        !self.restricted.contains(feed) || api_key.map_or(false, |api_key| api_key.may_use(feed))
+    }
+
+    //whether what's sent for the feed depends on the key, so shared caches in front mustn't keep it for everyone
+    pub fn is_private(&self, feed: &str) -> bool {
+        self.require_key || self.restricted.contains(feed)
+    }
+
+    //restricted feeds the consumer isn't allowed to see, to leave out of listings
+    pub fn hidden(&self, api_key: Option<&ApiKey>) -> HashSet<String> {
+        self.restricted
+            .iter()
+            .filter(|feed| !self.can_see(feed, api_key))
+            .cloned()
+            .collect()
+    }
+
+    //decides whether a request goes through, counting it against the key's quota and usage
+    //only memory is touched, quotas shared by several server processes can be overshot by what's made between flushes
+    pub fn check(
+        &self,
+        key: Option<&str>,
+        client: &str,
+        feed: Option<&str>,
+    ) -> Result<Option<&ApiKey>, Denied> {
+        let api_key = match key {
+            Some(key) => match self.keys.get(key) {
+                Some(api_key) => api_key,
+                None => return Err(Denied::UnknownKey),
+            },
+            None if self.require_key => return Err(Denied::MissingKey),
+            None => {
+                if let Some((rate, burst)) = self.anonymous {
+                    self.limiter
+                        .check(&format!("client|{}", client), rate, burst)
+                        .map_err(Denied::RateLimited)?;
+                }
+                return Ok(None);
+            }
+        };
+
+        self.limiter
+            .check(&format!("key|{}", api_key.name), api_key.rate, api_key.burst)
+            .map_err(Denied::RateLimited)?;
+
+        let date = Utc::now().format("%Y-%m-%d").to_string();
+        let mut usage = self.usage.lock().unwrap();
+        if !usage.count(&date, &api_key.name, feed, api_key.daily_quota) {
+            return Err(Denied::QuotaExceeded(until_midnight()));
+        }
+
+        Ok(Some(api_key))
+    }
+
+    //writes the requests counted since the last flush to redis
+    //and picks up what other server processes counted, so quotas hold across all of them
+    pub fn flush_usage(&self, con: &mut Connection) -> RedisResult<()> {
+        let pending = std::mem::take(&mut self.usage.lock().unwrap().pending);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let mut requests: HashMap<(&str, &str), u64> = HashMap::new();
+        for ((date, name, _), count) in pending.iter() {
+            *requests.entry((date, name)).or_insert(0) += count;
+        }
+        let requests: Vec<((&str, &str), u64)> = requests.into_iter().collect();
+
+        let mut pipe = redis::pipe();
+        for ((date, name), count) in requests.iter() {
+            let quota_key = format!("gtfsrtquota|{}|{}", name, date);
+            pipe.incr(&quota_key, *count)
+                .expire(&quota_key, QUOTA_KEY_TTL_SECONDS)
+                .ignore();
+        }
+        for ((date, name, feed), count) in pending.iter() {
+            let usage_key = format!("gtfsrtusage|{}|{}", name, date);
+            pipe.hincr(&usage_key, feed, *count)
+                .ignore()
+                .expire(&usage_key, USAGE_KEY_TTL_SECONDS)
+                .ignore();
+        }
+        let totals: RedisResult<Vec<u64>> = pipe.query(con);
+
+        let mut usage = self.usage.lock().unwrap();
+        let totals = match totals {
+            Ok(totals) => totals,
+            Err(e) => {
+                //kept for the next flush, used already counts them
+                for (key, count) in pending {
+                    *usage.pending.entry(key).or_insert(0) += count;
+                }
+                return Err(e);
+            }
+        };
+        for (((date, name), _), total) in requests.iter().zip(totals) {
+            if *date != usage.date {
+                continue;
+            }
+            let since: u64 = usage
+                .pending
+                .iter()
+                .filter(|((pending_date, pending_name, _), _)| {
+                    pending_date == date && pending_name == name
+                })
+                .map(|(_, count)| count)
+                .sum();
+            usage.used.insert(name.to_string(), total + since);
+        }
+        Ok(())
+    }
+}
+
+//flushes usage counts every FLUSH_INTERVAL, on its own thread since the redis connection is blocking
+pub fn flush_usage(access: Arc<AccessControl>) {
+    thread::spawn(move || {
+        let mut con: Option<Connection> = None;
+        loop {
+            thread::sleep(FLUSH_INTERVAL);
+            if access.usage.lock().unwrap().pending.is_empty() {
+                continue;
+            }
+            if con.is_none() {
+                con = redis::Client::open("redis://127.0.0.1:6379/")
+                    .and_then(|redisclient| redisclient.get_connection())
+                    .map_err(|e| println!("Could not connect to count API key usage: {}", e))
+                    .ok();
+            }
+            if let Some(connection) = con.as_mut() {
+                if let Err(e) = access.flush_usage(connection) {
+                    println!("Could not count API key usage: {}", e);
+                    con = None;
+                }
+            }
+        }
+    });
+}
+
+//the addresses in Forwarded, or X-Forwarded-For without it, oldest first
+//each header may have been sent several times, pass them joined with commas
+pub fn forwarded_hops(forwarded: Option<&str>, x_forwarded_for: Option<&str>) -> Vec<String> {
+    let hops: Vec<&str> = match (forwarded, x_forwarded_for) {
+        (Some(forwarded), _) => forwarded
+            .split(',')
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    match name.trim().eq_ignore_ascii_case("for") {
+                        true => Some(value),
+                        false => None,
+                    }
+                })
+            })
+            .collect(),
+        (None, Some(x_forwarded_for)) => x_forwarded_for.split(',').collect(),
+        (None, None) => Vec::new(),
+    };
+    hops.into_iter()
+        .map(hop_address)
+        .filter(|hop| !hop.is_empty())
+        .collect()
+}
+
+//drops the quotes, brackets and port a hop may come with
+fn hop_address(hop: &str) -> String {
+    let hop = hop.trim().trim_matches('"');
+    if let Some(bracketed) = hop.strip_prefix('[') {
+        return bracketed.split(']').next().unwrap_or(bracketed).to_string();
+    }
+    if hop.parse::<IpAddr>().is_ok() {
+        return hop.to_string();
+    }
+    match hop.rsplit_once(':') {
+        Some((address, _)) if address.parse::<Ipv4Addr>().is_ok() => address.to_string(),
+        _ => hop.to_string(),
+    }
+}
+
+fn until_midnight() -> Duration {
+    let now = Utc::now();
+    let midnight = (now.date_naive() + ChronoDuration::days(1))
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    (midnight - now).to_std().unwrap_or(Duration::from_secs(1))
+}
+
+//requests a key made on a day, by feed
+pub fn usage(con: &mut Connection, name: &str, date: &str) -> RedisResult<HashMap<String, u64>> {
+    redis::cmd("HGETALL")
+        .arg(format!("gtfsrtusage|{}|{}", name, date))
+        .query(con)
+}
+
+//compares secrets without stopping at the first differing byte, so timing doesn't reveal how much matched
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_uses_each_buckets_own_rate() {
+        let limiter = RateLimiter::default();
+        limiter.check("slow", 0.001, 1.0).unwrap();
+        limiter.check("fast", 1000.0, 1.0).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        limiter.buckets.lock().unwrap().swept = Instant::now() - SWEEP_INTERVAL;
+        limiter.check("other", 0.001, 1.0).unwrap();
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.buckets.contains_key("slow"));
+        assert!(!buckets.buckets.contains_key("fast"));
+    }
+
+    fn behind(proxies: &[&str]) -> AccessControl {
+        AccessControl::new(
+            HashMap::new(),
+            HashSet::new(),
+            false,
+            Some((1.0, 1.0)),
+            proxies.iter().map(|proxy| proxy.parse().unwrap()).collect(),
+        )
+    }
+
+    #[test]
+    fn forwarded_address_only_from_trusted_proxies() {
+        let access = behind(&["10.0.0.1"]);
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let stranger: IpAddr = "192.0.2.7".parse().unwrap();
+        let hops = forwarded_hops(None, Some("203.0.113.9"));
+        assert_eq!(access.client_address(Some(stranger), &hops), "192.0.2.7");
+        assert_eq!(access.client_address(Some(proxy), &hops), "203.0.113.9");
+        assert_eq!(access.client_address(Some(proxy), &[]), "10.0.0.1");
+        assert_eq!(access.client_address(None, &hops), "203.0.113.9");
+    }
+
+    #[test]
+    fn spoofed_leftmost_forwarded_entry_is_ignored() {
+        let access = behind(&["10.0.0.1", "10.0.0.2"]);
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        //the client sent X-Forwarded-For: 198.51.100.1 and both proxies appended to it
+        let hops = forwarded_hops(None, Some("198.51.100.1, 203.0.113.9, 10.0.0.2"));
+        assert_eq!(access.client_address(Some(proxy), &hops), "203.0.113.9");
+        let hops = forwarded_hops(
+            Some(r#"for=198.51.100.1, for="203.0.113.9:4711";proto=https, for="[2001:db8::1]""#),
+            Some("192.0.2.1"),
+        );
+        assert_eq!(hops, vec!["198.51.100.1", "203.0.113.9", "2001:db8::1"]);
+        assert_eq!(access.client_address(Some(proxy), &hops), "2001:db8::1");
+    }
+
+    fn limited(daily_quota: u64) -> AccessControl {
+        let key = ApiKey {
+            key: String::from("secret"),
+            name: String::from("consumer"),
+            rate: 1000.0,
+            burst: 1000.0,
+            daily_quota,
+            restricted_feeds: String::new(),
+        };
+        AccessControl::new(
+            HashMap::from([(key.key.clone(), key)]),
+            HashSet::new(),
+            false,
+            None,
+            HashSet::new(),
+        )
+    }
+
+    #[test]
+    fn quota_is_counted_in_memory() {
+        let access = limited(2);
+        assert!(access.check(Some("secret"), "", Some("f-a")).is_ok());
+        assert!(access.check(Some("secret"), "", Some("f-b")).is_ok());
+        assert!(matches!(
+            access.check(Some("secret"), "", Some("f-a")),
+            Err(Denied::QuotaExceeded(_))
+        ));
+        //turned away requests aren't counted as usage
+        let usage = access.usage.lock().unwrap();
+        assert_eq!(usage.pending.values().sum::<u64>(), 2);
+        assert_eq!(usage.used["consumer"], 2);
+    }
+
+    #[test]
+    fn quota_starts_over_on_a_new_day() {
+        let mut usage = UsageCounts::default();
+        assert!(usage.count("2024-01-01", "consumer", None, 1));
+        assert!(!usage.count("2024-01-01", "consumer", None, 1));
+        assert!(usage.count("2024-01-02", "consumer", None, 1));
+        assert_eq!(usage.pending.len(), 2);
+    }
+}
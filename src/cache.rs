@@ -0,0 +1,354 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, OnceLock, RwLock},
+};
+
+use actix_web::web::Bytes;
+use gtfs_rt::FeedMessage;
+use prost::Message;
+use redis::{Commands, Connection, RedisResult};
+
+use crate::{
+    compression::{compress, Encoding},
+    diff::diff,
+    error::FeedError,
+    filter::EntityFilter,
+    feed_hash,
+    geo::VehicleIndex,
+    metrics::metrics,
+    parse_protobuf_message,
+};
+
+//older snapshots kept per feed so differential feeds can be made against them
+pub const HISTORY_LENGTH: usize = 8;
+//clients asking for a diff against something older than this get the full dataset
+pub const MAX_DIFF_BASE_AGE_MILLIS: u64 = 15 * 60 * 1000;
+
+//one stored version of a feed, identified by its gtfsrttime
+//decoding and hashing happen once when the snapshot is loaded
+//compressed variants and json are made the first time a client asks for them and then reused
+pub struct Snapshot {
+    pub time: u64,
+    pub bytes: Bytes,
+    pub proto: Result<FeedMessage, String>,
+    pub hash: u64,
+    //seconds between fetches as configured in the ingest engine, if it told us
+    pub fetch_interval: Option<f32>,
+    json: OnceLock<String>,
+    vehicle_index: OnceLock<VehicleIndex>,
+    //encoded differential messages from older snapshots, keyed by their time
+    diffs: Mutex<HashMap<u64, Bytes>>,
+    gzip: OnceLock<Bytes>,
+    brotli: OnceLock<Bytes>,
+    zstd: OnceLock<Bytes>,
+}
+
+impl Snapshot {
+    pub fn new(time: u64, bytes: Vec<u8>) -> Snapshot {
+        let proto = parse_protobuf_message(&bytes).map_err(|e| e.to_string());
+        let hash = feed_hash(&bytes);
+        Snapshot {
+            time,
+            bytes: Bytes::from(bytes),
+            proto,
+            hash,
+            fetch_interval: None,
+            json: OnceLock::new(),
+            vehicle_index: OnceLock::new(),
+            diffs: Mutex::new(HashMap::new()),
+            gzip: OnceLock::new(),
+            brotli: OnceLock::new(),
+            zstd: OnceLock::new(),
+        }
+    }
+
+    fn compressed(&self, encoding: Encoding) -> Option<&OnceLock<Bytes>> {
+        match encoding {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some(&self.gzip),
+            Encoding::Brotli => Some(&self.brotli),
+            Encoding::Zstd => Some(&self.zstd),
+        }
+    }
+
+    //the snapshot in an encoding, None if no one asked for it yet so it still has to be compressed
+    pub fn encoded(&self, encoding: Encoding) -> Option<Bytes> {
+        match self.compressed(encoding) {
+            Some(cell) => cell.get().cloned(),
+            None => Some(self.bytes.clone()),
+        }
+    }
+
+    //compresses the snapshot unless that was already done, which blocks, see compress
+    pub fn encode(&self, encoding: Encoding) -> Bytes {
+        match self.compressed(encoding) {
+            Some(cell) => cell
+                .get_or_init(|| Bytes::from(compress(&self.bytes, encoding)))
+                .clone(),
+            None => self.bytes.clone(),
+        }
+    }
+
+    //the hash as websocket and sse clients get it, a string since javascript can't hold a u64
+    pub fn hash_string(&self) -> String {
+        self.hash.to_string()
+    }
+
+    //the header timestamp of the feed itself, in seconds
+    pub fn header_timestamp(&self) -> Option<u64> {
+        self.proto.as_ref().ok()?.header.timestamp
+    }
+
+    //built the first time someone filters this snapshot by area
+    pub fn vehicle_index(&self) -> Option<&VehicleIndex> {
+        let proto = self.proto.as_ref().ok()?;
+        Some(self.vehicle_index.get_or_init(|| VehicleIndex::new(proto)))
+    }
+
+    //applies a filter, using the spatial index when it has an area
+    pub fn filtered(&self, filter: &EntityFilter) -> Option<FeedMessage> {
+        let proto = self.proto.as_ref().ok()?;
+        match filter.area {
+            Some(_) => Some(filter.apply_indexed(proto, self.vehicle_index()?)),
+            None => Some(filter.apply(proto)),
+        }
+    }
+
+    //encoded DIFFERENTIAL message going from base to this snapshot
+    pub fn diff_from(&self, base: &Base) -> Option<Bytes> {
+        if let Some(encoded) = self.diffs.lock().unwrap().get(&base.time) {
+            return Some(encoded.clone());
+        }
+        let proto = self.proto.as_ref().ok()?;
+        let differential = match base.time == self.time {
+            true => diff(proto, proto),
+            false => diff(&base.message()?, proto),
+        };
+        let encoded = Bytes::from(differential.encode_to_vec());
+        self.diffs
+            .lock()
+            .unwrap()
+            .insert(base.time, encoded.clone());
+        Some(encoded)
+    }
+
+    //the decoded message, or why the stored bytes aren't one
+    pub fn message(&self, feed: &str, category: &str) -> Result<&FeedMessage, FeedError> {
+        self.proto
+            .as_ref()
+            .map_err(|reason| FeedError::UpstreamInvalid {
+                feed: feed.to_string(),
+                category: category.to_string(),
+                reason: reason.clone(),
+            })
+    }
+
+    //fails when the snapshot was fetched more than max_age seconds ago
+    pub fn check_age(
+        &self,
+        feed: &str,
+        category: &str,
+        max_age: Option<f64>,
+        now_millis: u64,
+    ) -> Result<(), FeedError> {
+        let age = now_millis.saturating_sub(self.time) as f64 / 1000.0;
+        match max_age {
+            Some(max_age) if age > max_age => Err(FeedError::Stale {
+                feed: feed.to_string(),
+                category: category.to_string(),
+                age,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    //the whole feed as json, None if the protobuf didn't decode
+    pub fn json(&self) -> Option<&str> {
+        let proto = self.proto.as_ref().ok()?;
+        Some(self.json.get_or_init(|| serde_json::to_string(proto).unwrap()))
+    }
+}
+
+//what's kept of an older snapshot to make diffs against
+//only the encoded message, which is shared with the snapshot, so its decoded message,
+//compressed variants, json and index are freed once it's replaced
+#[derive(Clone)]
+pub struct Base {
+    pub time: u64,
+    bytes: Bytes,
+}
+
+impl Base {
+    pub fn of(snapshot: &Snapshot) -> Base {
+        Base {
+            time: snapshot.time,
+            bytes: snapshot.bytes.clone(),
+        }
+    }
+
+    //decoded again every time, only diffs that aren't cached on the current snapshot need it
+    pub fn message(&self) -> Option<FeedMessage> {
+        parse_protobuf_message(&self.bytes).ok()
+    }
+}
+
+#[derive(Default)]
+pub struct SnapshotCache {
+    snapshots: RwLock<HashMap<String, Arc<Snapshot>>>,
+    history: RwLock<HashMap<String, VecDeque<Base>>>,
+}
+
+impl SnapshotCache {
+    pub fn new() -> SnapshotCache {
+        SnapshotCache::default()
+    }
+
+    //returns the current snapshot for a feed, only reading the bytes from redis when gtfsrttime moved
+    pub fn load(
+        &self,
+        con: &mut Connection,
+        feed: &str,
+        category: &str,
+    ) -> RedisResult<Arc<Snapshot>> {
+        let time = con.get::<String, u64>(format!("gtfsrttime|{}|{}", feed, category))?;
+        let key = format!("{}|{}", feed, category);
+
+        if let Some(snapshot) = self.snapshots.read().unwrap().get(&key) {
+            if snapshot.time == time {
+                metrics().inc("kactus_snapshot_cache_total", &[("result", "hit")], 1.0);
+                return Ok(snapshot.clone());
+            }
+        }
+
+        metrics().inc("kactus_snapshot_cache_total", &[("result", "miss")], 1.0);
+        let bytes = con.get::<String, Vec<u8>>(format!("gtfsrt|{}|{}", feed, category))?;
+        let mut snapshot = Snapshot::new(time, bytes);
+        snapshot.fetch_interval = con.get::<String, Option<f32>>(format!("gtfsrtinterval|{}", feed))?;
+        let snapshot = Arc::new(snapshot);
+        self.store(key, snapshot.clone());
+        Ok(snapshot)
+    }
+
+    //makes a snapshot current, moving the one it replaces into the history
+    fn store(&self, key: String, snapshot: Arc<Snapshot>) {
+        let time = snapshot.time;
+        let replaced = self
+            .snapshots
+            .write()
+            .unwrap()
+            .insert(key.clone(), snapshot);
+
+        if let Some(replaced) = replaced {
+            let mut history = self.history.write().unwrap();
+            let older = history.entry(key).or_default();
+            //a snapshot that didn't decode can't be diffed against
+            if replaced.time < time
+                && replaced.proto.is_ok()
+                && older.iter().all(|older| older.time != replaced.time)
+            {
+                older.push_front(Base::of(&replaced));
+            }
+            older.truncate(HISTORY_LENGTH);
+            older.retain(|older| time.saturating_sub(older.time) <= MAX_DIFF_BASE_AGE_MILLIS);
+        }
+    }
+
+    //like load, but tells a feed or category that doesn't exist apart from redis failing
+    pub fn snapshot(
+        &self,
+        con: &mut Connection,
+        feed: &str,
+        category: &str,
+    ) -> Result<Arc<Snapshot>, FeedError> {
+        self.load(con, feed, category)
+            .map_err(|e| FeedError::from_redis(con, feed, category, e))
+    }
+
+    //an earlier snapshot of a feed, if it's still kept
+    pub fn previous(&self, feed: &str, category: &str, time: u64) -> Option<Base> {
+        let key = format!("{}|{}", feed, category);
+        self.history
+            .read()
+            .unwrap()
+            .get(&key)?
+            .iter()
+            .find(|older| older.time == time)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(time: u64) -> Arc<Snapshot> {
+        Arc::new(Snapshot::new(time, FeedMessage::default().encode_to_vec()))
+    }
+
+    #[test]
+    fn replaced_snapshots_are_kept_as_bases() {
+        let cache = SnapshotCache::new();
+        cache.store(String::from("f|vehicles"), snapshot(1000));
+        cache.store(String::from("f|vehicles"), snapshot(2000));
+        assert_eq!(cache.previous("f", "vehicles", 1000).unwrap().time, 1000);
+        assert!(cache.previous("f", "vehicles", 2000).is_none());
+        assert!(cache.previous("f", "trips", 1000).is_none());
+    }
+
+    #[test]
+    fn bases_expire_so_clients_get_a_full_dataset() {
+        let cache = SnapshotCache::new();
+        cache.store(String::from("f|vehicles"), snapshot(1000));
+        cache.store(String::from("f|vehicles"), snapshot(2000));
+        cache.store(
+            String::from("f|vehicles"),
+            snapshot(1000 + MAX_DIFF_BASE_AGE_MILLIS + 1),
+        );
+        assert!(cache.previous("f", "vehicles", 1000).is_none());
+        assert!(cache.previous("f", "vehicles", 2000).is_some());
+    }
+
+    #[test]
+    fn diffs_against_a_kept_base() {
+        let cache = SnapshotCache::new();
+        let mut base = FeedMessage::default();
+        base.entity.push(gtfs_rt::FeedEntity {
+            id: String::from("gone"),
+            ..gtfs_rt::FeedEntity::default()
+        });
+        cache.store(
+            String::from("f|vehicles"),
+            Arc::new(Snapshot::new(1000, base.encode_to_vec())),
+        );
+        let current = snapshot(2000);
+        cache.store(String::from("f|vehicles"), current.clone());
+
+        let base = cache.previous("f", "vehicles", 1000).unwrap();
+        let differential = FeedMessage::decode(current.diff_from(&base).unwrap()).unwrap();
+        assert_eq!(differential.entity.len(), 1);
+        assert_eq!(differential.entity[0].is_deleted, Some(true));
+
+        let unchanged = current.diff_from(&Base::of(&current)).unwrap();
+        let unchanged = FeedMessage::decode(unchanged).unwrap();
+        assert!(unchanged.entity.is_empty());
+    }
+
+    #[test]
+    fn undecodable_snapshots_are_not_kept() {
+        let cache = SnapshotCache::new();
+        let undecodable = Arc::new(Snapshot::new(1000, vec![0xff]));
+        cache.store(String::from("f|vehicles"), undecodable);
+        cache.store(String::from("f|vehicles"), snapshot(2000));
+        assert!(cache.previous("f", "vehicles", 1000).is_none());
+    }
+
+    #[test]
+    fn history_is_capped() {
+        let cache = SnapshotCache::new();
+        for time in 0..=(HISTORY_LENGTH as u64 + 1) {
+            cache.store(String::from("f|vehicles"), snapshot(time));
+        }
+        assert!(cache.previous("f", "vehicles", 0).is_none());
+        assert!(cache.previous("f", "vehicles", 2).is_some());
+    }
+}
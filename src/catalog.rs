@@ -0,0 +1,468 @@
+use std::collections::{HashMap, HashSet};
+
+use redis::{Commands, Connection, RedisResult};
+
+use crate::{health::HealthThresholds, AgencyInfo};
+
+//every feed ever inserted, all with score 0 so they can be paged through in name order
+pub const FEEDS_KEY: &str = "gtfsrtfeeds";
+pub const CATEGORIES: [&str; 3] = ["vehicles", "trips", "alerts"];
+
+//hash holding what the catalog knows about one feed
+//static fields come from the ingest config, the rest are prefixed with the category
+fn feed_key(feed: &str) -> String {
+    format!("gtfsrtcatalog|{}", feed)
+}
+
+//written by the ingest engine when it loads its config
+pub fn record_metadata(con: &mut Connection, agency: &AgencyInfo) -> RedisResult<()> {
+    let configured: Vec<&str> = [
+        ("vehicles", &agency.realtime_vehicle_positions),
+        ("trips", &agency.realtime_trip_updates),
+        ("alerts", &agency.realtime_alerts),
+    ]
+    .iter()
+    .filter(|(_, url)| !url.is_empty())
+    .map(|(category, _)| *category)
+    .collect();
+
+    redis::pipe()
+        .hset_multiple(
+            feed_key(&agency.onetrip),
+            &[
+                ("interval", agency.fetch_interval.to_string()),
+                ("has_auth", agency.has_auth.to_string()),
+                ("configured", configured.join(",")),
+            ],
+        )
+        .ignore()
+        .zadd(FEEDS_KEY, &agency.onetrip, 0)
+        .ignore()
+        .query(con)
+}
+
+//written with every snapshot, changed only moves when the contents did
+pub fn record_snapshot(
+    con: &mut Connection,
+    feed: &str,
+    category: &str,
+    time: u64,
+    hash: u64,
+    size: usize,
+    entities: Option<usize>,
+) -> RedisResult<()> {
+    let key = feed_key(feed);
+    let previous_hash = con.hget::<_, _, Option<String>>(&key, format!("{}|hash", category))?;
+
+    let mut fields = vec![
+        (format!("{}|fetched", category), time.to_string()),
+        (format!("{}|hash", category), hash.to_string()),
+        (format!("{}|size", category), size.to_string()),
+    ];
+    if previous_hash != Some(hash.to_string()) {
+        fields.push((format!("{}|changed", category), time.to_string()));
+    }
+    if let Some(entities) = entities {
+        fields.push((format!("{}|entities", category), entities.to_string()));
+    }
+
+    redis::pipe()
+        .hset_multiple(&key, &fields)
+        .ignore()
+        .zadd(FEEDS_KEY, feed, 0)
+        .ignore()
+        .query(con)
+}
+
+//fills the catalog from the gtfsrtexists and gtfsrttime keys of ingesters that predate it
+//runs on every start, feeds already in the catalog are left alone, and uses SCAN so redis isn't blocked
+pub fn backfill(con: &mut Connection) -> RedisResult<usize> {
+    let feeds: Vec<String> = con
+        .scan_match::<_, String>("gtfsrtexists|*")?
+        .filter_map(|key| key.strip_prefix("gtfsrtexists|").map(|feed| feed.to_string()))
+        .collect();
+    let mut added = 0;
+    for feed in feeds.iter() {
+        if con.zscore::<_, _, Option<f64>>(FEEDS_KEY, feed)?.is_some() {
+            continue;
+        }
+        let mut fields: Vec<(String, String)> = Vec::new();
+        for category in CATEGORIES {
+            if let Ok(time) = con.get::<_, u64>(format!("gtfsrttime|{}|{}", feed, category)) {
+                fields.push((format!("{}|fetched", category), time.to_string()));
+            }
+        }
+        if let Ok(interval) = con.get::<_, f32>(format!("gtfsrtinterval|{}", feed)) {
+            fields.push((String::from("interval"), interval.to_string()));
+        }
+        let mut pipe = redis::pipe();
+        if !fields.is_empty() {
+            pipe.hset_multiple(feed_key(feed), &fields).ignore();
+        }
+        //NX so a feed an ingester added in the meantime isn't counted twice
+        let (newly_added,): (usize,) = pipe
+            .cmd("ZADD")
+            .arg(FEEDS_KEY)
+            .arg("NX")
+            .arg(0)
+            .arg(feed)
+            .query(con)?;
+        added += newly_added;
+    }
+    Ok(added)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CategoryStatus {
+    pub category: String,
+    //last time a snapshot was inserted, in milliseconds
+    pub fetched: u64,
+    //last time the snapshot was different from the one before it, in milliseconds
+    pub changed: Option<u64>,
+    pub entities: Option<usize>,
+    pub size: Option<usize>,
+    //seconds since fetched
+    pub age: f64,
+    pub stale: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedStatus {
+    pub feed: String,
+    //categories the ingest config has a url for
+    pub configured: Vec<String>,
+    pub fetch_interval: Option<f32>,
+    pub has_auth: Option<bool>,
+    //categories that have data
+    pub categories: Vec<CategoryStatus>,
+}
+
+impl FeedStatus {
+    //a category is stale once /health would call it degraded for its age
+    fn from_fields(
+        feed: &str,
+        fields: &HashMap<String, String>,
+        thresholds: &HealthThresholds,
+        now_millis: u64,
+    ) -> FeedStatus {
+        let fetch_interval = fields
+            .get("interval")
+            .and_then(|interval| interval.parse::<f32>().ok());
+        let stale_after = thresholds.threshold(feed, fetch_interval).degraded_after;
+        let field = |category: &str, name: &str| fields.get(&format!("{}|{}", category, name));
+
+        let categories = CATEGORIES
+            .iter()
+            .filter_map(|category| {
+                let fetched = field(category, "fetched")?.parse::<u64>().ok()?;
+                let age = now_millis.saturating_sub(fetched) as f64 / 1000.0;
+                Some(CategoryStatus {
+                    category: category.to_string(),
+                    fetched,
+                    changed: field(category, "changed").and_then(|changed| changed.parse().ok()),
+                    entities: field(category, "entities").and_then(|entities| entities.parse().ok()),
+                    size: field(category, "size").and_then(|size| size.parse().ok()),
+                    age,
+                    stale: age > stale_after,
+                })
+            })
+            .collect();
+
+        FeedStatus {
+            feed: feed.to_string(),
+            configured: fields
+                .get("configured")
+                .map(|configured| {
+                    configured
+                        .split(',')
+                        .filter(|category| !category.is_empty())
+                        .map(|category| category.to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            fetch_interval,
+            has_auth: fields.get("has_auth").and_then(|has_auth| has_auth.parse().ok()),
+            categories,
+        }
+    }
+
+    pub fn category(&self, category: &str) -> Option<&CategoryStatus> {
+        self.categories
+            .iter()
+            .find(|status| status.category == category)
+    }
+}
+
+//which feeds to list, all optional
+#[derive(Debug, Default, Clone)]
+pub struct CatalogQuery {
+    pub prefix: Option<String>,
+    //only feeds with data for this category
+    pub category: Option<String>,
+    //only feeds fetched within this many seconds, in the category if one is given
+    pub max_age: Option<f64>,
+    //only stale or only fresh feeds
+    pub stale: Option<bool>,
+    //feed name the previous page ended on
+    pub after: Option<String>,
+    pub limit: usize,
+    //feeds never listed, like restricted ones the consumer can't use
+    pub hidden: HashSet<String>,
+}
+
+impl CatalogQuery {
+    fn matches(&self, status: &FeedStatus) -> bool {
+        if self.hidden.contains(&status.feed) {
+            return false;
+        }
+        let categories: Vec<&CategoryStatus> = match &self.category {
+            Some(category) => status.category(category).into_iter().collect(),
+            None => status.categories.iter().collect(),
+        };
+        if self.category.is_some() && categories.is_empty() {
+            return false;
+        }
+        if let Some(max_age) = self.max_age {
+            if !categories.iter().any(|category| category.age <= max_age) {
+                return false;
+            }
+        }
+        if let Some(stale) = self.stale {
+            //a feed is stale when everything it has is stale
+            let feed_stale = categories.iter().all(|category| category.stale);
+            if feed_stale != stale {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CatalogPage {
+    pub feeds: Vec<FeedStatus>,
+    //pass as after= to get the next page, None on the last one
+    pub next: Option<String>,
+}
+
+//what the catalog knows about one feed, None if it was never inserted
+pub fn status(
+    con: &mut Connection,
+    feed: &str,
+    thresholds: &HealthThresholds,
+    now_millis: u64,
+) -> RedisResult<Option<FeedStatus>> {
+    let fields: HashMap<String, String> = con.hgetall(feed_key(feed))?;
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(FeedStatus::from_fields(
+        feed, &fields, thresholds, now_millis,
+    )))
+}
+
+//how many feed names are read from the sorted set at a time while filtering
+const SCAN_BATCH: isize = 200;
+
+pub fn list(
+    con: &mut Connection,
+    query: &CatalogQuery,
+    thresholds: &HealthThresholds,
+    now_millis: u64,
+) -> RedisResult<CatalogPage> {
+    page(query, thresholds, now_millis, |min, max| {
+        let names: Vec<String> = con.zrangebylex_limit(FEEDS_KEY, min, max, 0, SCAN_BATCH)?;
+        if names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = redis::pipe();
+        for name in names.iter() {
+            pipe.hgetall(feed_key(name));
+        }
+        let fields: Vec<HashMap<String, String>> = pipe.query(con)?;
+        Ok(names.into_iter().zip(fields).collect())
+    })
+}
+
+//feeds in a ZRANGEBYLEX range with their catalog fields, at most SCAN_BATCH of them
+type Batch = Vec<(String, HashMap<String, String>)>;
+
+//walks the feeds in name order from the cursor a batch at a time until the page is full
+fn page(
+    query: &CatalogQuery,
+    thresholds: &HealthThresholds,
+    now_millis: u64,
+    mut read_batch: impl FnMut(&[u8], &[u8]) -> RedisResult<Batch>,
+) -> RedisResult<CatalogPage> {
+    //lexicographic range in the sorted set, bytes so the 0xff upper bound survives
+    let prefix = query.prefix.clone().unwrap_or_default();
+    let mut min: Vec<u8> = match &query.after {
+        Some(after) if after.as_str() >= prefix.as_str() => [b"(", after.as_bytes()].concat(),
+        _ if !prefix.is_empty() => [b"[", prefix.as_bytes()].concat(),
+        _ => b"-".to_vec(),
+    };
+    let max: Vec<u8> = if prefix.is_empty() {
+        b"+".to_vec()
+    } else {
+        [b"[", prefix.as_bytes(), &[0xff]].concat()
+    };
+
+    let mut feeds: Vec<FeedStatus> = Vec::new();
+    loop {
+        let batch = read_batch(&min, &max)?;
+        let last = match batch.last() {
+            Some((name, _)) => name.clone(),
+            None => return Ok(CatalogPage { feeds, next: None }),
+        };
+
+        for (name, fields) in batch.iter() {
+            let status = FeedStatus::from_fields(name, fields, thresholds, now_millis);
+            if query.matches(&status) {
+                feeds.push(status);
+                if feeds.len() >= query.limit {
+                    return Ok(CatalogPage {
+                        feeds,
+                        next: Some(name.clone()),
+                    });
+                }
+            }
+        }
+
+        if (batch.len() as isize) < SCAN_BATCH {
+            return Ok(CatalogPage { feeds, next: None });
+        }
+        min = [b"(", last.as_bytes()].concat();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //ZRANGEBYLEX over a sorted list of names, fields come from fields_of
+    fn sorted_set<'a>(
+        names: &'a [String],
+        fields_of: impl Fn(&str) -> HashMap<String, String> + 'a,
+        reads: &'a mut usize,
+    ) -> impl FnMut(&[u8], &[u8]) -> RedisResult<Batch> + 'a {
+        move |min, max| {
+            *reads += 1;
+            let above = |name: &[u8]| match min[0] {
+                b'-' => true,
+                b'(' => name > &min[1..],
+                _ => name >= &min[1..],
+            };
+            let below = |name: &[u8]| match max[0] {
+                b'+' => true,
+                b'(' => name < &max[1..],
+                _ => name <= &max[1..],
+            };
+            Ok(names
+                .iter()
+                .filter(|name| above(name.as_bytes()) && below(name.as_bytes()))
+                .take(SCAN_BATCH as usize)
+                .map(|name| (name.clone(), fields_of(name)))
+                .collect())
+        }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        let mut names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        names.sort();
+        names
+    }
+
+    fn list_names(names: &[String], query: &CatalogQuery) -> (Vec<String>, Option<String>) {
+        let mut reads = 0;
+        let read_batch = sorted_set(names, |_| HashMap::new(), &mut reads);
+        let page = page(query, &HealthThresholds::default(), 0, read_batch).unwrap();
+        let feeds = page.feeds.into_iter().map(|status| status.feed).collect();
+        (feeds, page.next)
+    }
+
+    fn query(prefix: Option<&str>, after: Option<&str>, limit: usize) -> CatalogQuery {
+        CatalogQuery {
+            prefix: prefix.map(|prefix| prefix.to_string()),
+            after: after.map(|after| after.to_string()),
+            limit,
+            ..CatalogQuery::default()
+        }
+    }
+
+    #[test]
+    fn pages_follow_name_order() {
+        let all = names(&["f-c", "f-a", "f-b", "f-d", "f-e"]);
+        let (feeds, next) = list_names(&all, &query(None, None, 2));
+        assert_eq!(feeds, vec!["f-a", "f-b"]);
+        assert_eq!(next.as_deref(), Some("f-b"));
+        let (feeds, next) = list_names(&all, &query(None, next.as_deref(), 2));
+        assert_eq!(feeds, vec!["f-c", "f-d"]);
+        let (feeds, next) = list_names(&all, &query(None, next.as_deref(), 2));
+        assert_eq!(feeds, vec!["f-e"]);
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn a_full_last_page_points_at_an_empty_one() {
+        let all = names(&["a", "b"]);
+        let (feeds, next) = list_names(&all, &query(None, None, 2));
+        assert_eq!(feeds, vec!["a", "b"]);
+        let (feeds, next) = list_names(&all, &query(None, next.as_deref(), 2));
+        assert!(feeds.is_empty());
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn cursors_outside_the_prefix() {
+        let all = names(&["f-a", "f-b", "g-a", "o-a"]);
+        //a cursor before the prefix starts at the prefix
+        let (feeds, _) = list_names(&all, &query(Some("f-"), Some("a"), 10));
+        assert_eq!(feeds, vec!["f-a", "f-b"]);
+        //a cursor after the prefix has nothing left
+        let (feeds, next) = list_names(&all, &query(Some("f-"), Some("z"), 10));
+        assert!(feeds.is_empty());
+        assert_eq!(next, None);
+        //a cursor that isn't a feed name continues after where it would be
+        let (feeds, _) = list_names(&all, &query(None, Some("f-az"), 10));
+        assert_eq!(feeds, vec!["f-b", "g-a", "o-a"]);
+    }
+
+    #[test]
+    fn filtered_pages_read_past_a_batch() {
+        let all: Vec<String> = (0..SCAN_BATCH * 2 + 10)
+            .map(|i| format!("f-{:04}", i))
+            .collect();
+        //only every hundredth feed has vehicles
+        let fields_of = |name: &str| {
+            let number: usize = name[2..].parse().unwrap();
+            match number % 100 {
+                0 => HashMap::from([(String::from("vehicles|fetched"), String::from("0"))]),
+                _ => HashMap::new(),
+            }
+        };
+        let query = CatalogQuery {
+            category: Some(String::from("vehicles")),
+            limit: 10,
+            ..CatalogQuery::default()
+        };
+        let mut reads = 0;
+        let page = page(
+            &query,
+            &HealthThresholds::default(),
+            0,
+            sorted_set(&all, fields_of, &mut reads),
+        )
+        .unwrap();
+        let feeds: Vec<&str> = page
+            .feeds
+            .iter()
+            .map(|status| status.feed.as_str())
+            .collect();
+        assert_eq!(
+            feeds,
+            vec!["f-0000", "f-0100", "f-0200", "f-0300", "f-0400"]
+        );
+        assert_eq!(page.next, None);
+        assert_eq!(reads, 3);
+    }
+}
@@ -0,0 +1,163 @@
+use std::io::Write;
+
+use flate2::{write::GzEncoder, Compression};
+
+//content encodings kactus can serve precompressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    pub fn header_value(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+//identity is acceptable unless excluded, but any encoding the client names beats it
+const IMPLICIT_IDENTITY_Q: f32 = f32::MIN_POSITIVE;
+
+//picks the best encoding we support from an Accept-Encoding header
+//None when the client excluded everything we have, identity included
+pub fn choose_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let mut named: Vec<(Encoding, f32)> = Vec::new();
+    //* covers every encoding that isn't named
+    let mut wildcard: Option<f32> = None;
+
+    for part in accept_encoding.split(',') {
+        let mut pieces = part.split(';');
+        let name = pieces.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = pieces
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .next()
+            .map(|q| q.trim().parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+
+        let encoding = match name.as_str() {
+            "br" => Encoding::Brotli,
+            "zstd" => Encoding::Zstd,
+            "gzip" | "x-gzip" => Encoding::Gzip,
+            "identity" => Encoding::Identity,
+            "*" => {
+                wildcard = Some(q);
+                continue;
+            }
+            _ => continue,
+        };
+        named.push((encoding, q));
+    }
+
+    let quality = |encoding: Encoding| {
+        named
+            .iter()
+            .find(|(named, _)| *named == encoding)
+            .map(|(_, q)| *q)
+            .or(wildcard)
+    };
+
+    let mut best = None;
+    let mut best_q = 0.0;
+    for encoding in [
+        Encoding::Identity,
+        Encoding::Gzip,
+        Encoding::Zstd,
+        Encoding::Brotli,
+    ] {
+        let q = match quality(encoding) {
+            Some(q) => q,
+            None if encoding == Encoding::Identity => IMPLICIT_IDENTITY_Q,
+            None => continue,
+        };
+        //encodings are tried in order of preference, so ties go to the later one
+        if q > 0.0 && q >= best_q {
+            best = Some(encoding);
+            best_q = q;
+        }
+    }
+
+    best
+}
+
+//brotli and zstd at level 9 take a while on big feeds, so don't call this on an async worker
+pub fn compress(bytes: &[u8], encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Identity => bytes.to_vec(),
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(bytes).unwrap();
+            encoder.finish().unwrap()
+        }
+        Encoding::Brotli => {
+            let mut output = Vec::new();
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut output, 4096, 9, 22);
+                writer.write_all(bytes).unwrap();
+            }
+            output
+        }
+        Encoding::Zstd => zstd::bulk::compress(bytes, 9).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_highest_quality() {
+        assert_eq!(
+            choose_encoding("gzip;q=0.5, br;q=0.4"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(choose_encoding("x-gzip"), Some(Encoding::Gzip));
+        assert_eq!(
+            choose_encoding("zstd;q=0.9, identity"),
+            Some(Encoding::Identity)
+        );
+    }
+
+    #[test]
+    fn ties_go_to_the_better_compression() {
+        assert_eq!(choose_encoding("gzip, br"), Some(Encoding::Brotli));
+        assert_eq!(choose_encoding("br, gzip, zstd"), Some(Encoding::Brotli));
+        assert_eq!(choose_encoding("gzip, zstd"), Some(Encoding::Zstd));
+    }
+
+    #[test]
+    fn identity_unless_excluded() {
+        assert_eq!(choose_encoding(""), Some(Encoding::Identity));
+        assert_eq!(choose_encoding("deflate"), Some(Encoding::Identity));
+        assert_eq!(choose_encoding("identity;q=0"), None);
+        assert_eq!(choose_encoding("deflate, identity;q=0"), None);
+        assert_eq!(choose_encoding("zstd, identity;q=0"), Some(Encoding::Zstd));
+    }
+
+    #[test]
+    fn wildcard_covers_what_isnt_named() {
+        assert_eq!(choose_encoding("*"), Some(Encoding::Brotli));
+        assert_eq!(choose_encoding("br;q=0, *"), Some(Encoding::Zstd));
+        assert_eq!(choose_encoding("gzip, *;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(choose_encoding("*;q=0"), None);
+        assert_eq!(choose_encoding("identity, *;q=0"), Some(Encoding::Identity));
+    }
+
+    #[test]
+    fn compressed_bytes_decode_back() {
+        let bytes = b"vehicle positions ".repeat(100);
+        let gzip = compress(&bytes, Encoding::Gzip);
+        let mut decoded = Vec::new();
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&gzip[..]), &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, bytes);
+        let zstd = compress(&bytes, Encoding::Zstd);
+        assert_eq!(zstd::bulk::decompress(&zstd, bytes.len()).unwrap(), bytes);
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::{HashMap, HashSet};
+
+use gtfs_rt::{feed_header::Incrementality, FeedEntity, FeedMessage};
+
+//entity level differences between two full datasets as a DIFFERENTIAL message
+//new and changed entities are sent whole, entities that went away get an is_deleted tombstone
+pub fn diff(base: &FeedMessage, current: &FeedMessage) -> FeedMessage {
+    let base_entities: HashMap<&str, &FeedEntity> = base
+        .entity
+        .iter()
+        .map(|entity| (entity.id.as_str(), entity))
+        .collect();
+    let current_entities: HashMap<&str, &FeedEntity> = current
+        .entity
+        .iter()
+        .map(|entity| (entity.id.as_str(), entity))
+        .collect();
+
+    let mut differential = FeedMessage::default();
+    differential.header = current.header.clone();
+    differential.header.incrementality = Some(Incrementality::Differential as i32);
+
+    for entity in current.entity.iter() {
+        match base_entities.get(entity.id.as_str()) {
+            Some(base_entity) if *base_entity == entity => {}
+            _ => differential.entity.push(entity.clone()),
+        }
+    }
+
+    let mut deleted: HashSet<&str> = HashSet::new();
+    for entity in base.entity.iter() {
+        if !current_entities.contains_key(entity.id.as_str()) && deleted.insert(entity.id.as_str()) {
+            let mut tombstone = FeedEntity::default();
+            tombstone.id = entity.id.clone();
+            tombstone.is_deleted = Some(true);
+            differential.entity.push(tombstone);
+        }
+    }
+
+    differential
+}
+
+#[cfg(test)]
+mod tests {
+    use gtfs_rt::{FeedHeader, VehiclePosition};
+
+    use super::*;
+
+    fn vehicle(id: &str, bearing: f32) -> FeedEntity {
+        let mut entity = FeedEntity::default();
+        entity.id = id.to_string();
+        entity.vehicle = Some(VehiclePosition {
+            position: Some(gtfs_rt::Position {
+                bearing: Some(bearing),
+                ..gtfs_rt::Position::default()
+            }),
+            ..VehiclePosition::default()
+        });
+        entity
+    }
+
+    fn message(timestamp: u64, entities: Vec<FeedEntity>) -> FeedMessage {
+        let mut message = FeedMessage::default();
+        message.header = FeedHeader {
+            timestamp: Some(timestamp),
+            ..FeedHeader::default()
+        };
+        message.entity = entities;
+        message
+    }
+
+    #[test]
+    fn adds_updates_and_deletes() {
+        let base = message(
+            1,
+            vec![
+                vehicle("same", 0.0),
+                vehicle("moved", 0.0),
+                vehicle("gone", 0.0),
+            ],
+        );
+        let current = message(
+            2,
+            vec![
+                vehicle("same", 0.0),
+                vehicle("moved", 90.0),
+                vehicle("new", 0.0),
+            ],
+        );
+        let differential = diff(&base, &current);
+
+        assert_eq!(
+            differential.header.incrementality,
+            Some(Incrementality::Differential as i32)
+        );
+        assert_eq!(differential.header.timestamp, Some(2));
+        let ids: Vec<&str> = differential.entity.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, ["moved", "new", "gone"]);
+        assert_eq!(differential.entity[0], vehicle("moved", 90.0));
+        assert_eq!(differential.entity[1], vehicle("new", 0.0));
+        assert_eq!(differential.entity[2].is_deleted, Some(true));
+        assert!(differential.entity[2].vehicle.is_none());
+    }
+
+    #[test]
+    fn unchanged_feed_has_no_entities() {
+        let base = message(1, vec![vehicle("a", 0.0), vehicle("b", 0.0)]);
+        assert!(diff(&base, &base).entity.is_empty());
+    }
+}
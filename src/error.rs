@@ -0,0 +1,274 @@
+use std::fmt;
+
+use redis::{Connection, ErrorKind, RedisError, RedisResult};
+use serde_json::{json, Value};
+
+use crate::catalog::FEEDS_KEY;
+
+//why a feed couldn't be served, shared by the http server and the ingest rpc
+//the code is stable, clients should match on it rather than on the message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum FeedError {
+    UnknownFeed {
+        feed: String,
+    },
+    //the feed exists but has never had data for the category
+    UnknownCategory {
+        feed: String,
+        category: String,
+    },
+    //the newest snapshot is older than the client accepts, age in seconds
+    Stale {
+        feed: String,
+        category: String,
+        age: f64,
+    },
+    //what's stored doesn't decode as a gtfs-rt message
+    UpstreamInvalid {
+        feed: String,
+        category: String,
+        reason: String,
+    },
+    //redis couldn't be reached or failed to answer
+    BackendUnavailable {
+        reason: String,
+    },
+    //a key is there but holds something that can't be read, like a time that isn't a number
+    Internal {
+        reason: String,
+    },
+}
+
+impl FeedError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            FeedError::UnknownFeed { .. } => "unknown_feed",
+            FeedError::UnknownCategory { .. } => "unknown_category",
+            FeedError::Stale { .. } => "stale",
+            FeedError::UpstreamInvalid { .. } => "upstream_invalid",
+            FeedError::BackendUnavailable { .. } => "backend_unavailable",
+            FeedError::Internal { .. } => "internal",
+        }
+    }
+
+    //http status the server answers with
+    pub fn status(&self) -> u16 {
+        match self {
+            FeedError::UnknownFeed { .. } | FeedError::UnknownCategory { .. } => 404,
+            FeedError::UpstreamInvalid { .. } => 502,
+            FeedError::Stale { .. } | FeedError::BackendUnavailable { .. } => 503,
+            FeedError::Internal { .. } => 500,
+        }
+    }
+
+    pub fn backend(error: RedisError) -> FeedError {
+        FeedError::BackendUnavailable {
+            reason: error.to_string(),
+        }
+    }
+
+    //works out why reading a feed's keys failed
+    //a missing key comes back as nil, which doesn't convert, but so does a key holding the wrong thing,
+    //so the keys are checked before calling it missing, anything else is redis itself failing
+    pub fn from_redis(
+        con: &mut impl FeedKeys,
+        feed: &str,
+        category: &str,
+        error: RedisError,
+    ) -> FeedError {
+        if error.kind() != ErrorKind::TypeError {
+            return FeedError::backend(error);
+        }
+        match con.category_exists(feed, category) {
+            Ok(true) => {
+                return FeedError::Internal {
+                    reason: error.to_string(),
+                }
+            }
+            Ok(false) => {}
+            Err(e) => return FeedError::backend(e),
+        }
+        match con.feed_exists(feed) {
+            Ok(true) => FeedError::UnknownCategory {
+                feed: feed.to_string(),
+                category: category.to_string(),
+            },
+            Ok(false) => FeedError::UnknownFeed {
+                feed: feed.to_string(),
+            },
+            Err(e) => FeedError::backend(e),
+        }
+    }
+
+    //the json body of the error response, the variant's fields are sent along with the code
+    pub fn body(&self) -> Value {
+        let mut error = serde_json::to_value(self).unwrap();
+        error["message"] = json!(self.to_string());
+        json!({ "error": error })
+    }
+}
+
+impl fmt::Display for FeedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeedError::UnknownFeed { feed } => write!(f, "no feed named {}", feed),
+            FeedError::UnknownCategory { feed, category } => {
+                write!(f, "{} has no {} data", feed, category)
+            }
+            FeedError::Stale {
+                feed,
+                category,
+                age,
+            } => {
+                write!(
+                    f,
+                    "{} {} was fetched {:.0} seconds ago",
+                    feed, category, age
+                )
+            }
+            FeedError::UpstreamInvalid {
+                feed,
+                category,
+                reason,
+            } => write!(
+                f,
+                "{} {} is not a valid GTFS-rt feed: {}",
+                feed, category, reason
+            ),
+            FeedError::BackendUnavailable { reason } => {
+                write!(f, "redis is unavailable: {}", reason)
+            }
+            FeedError::Internal { reason } => write!(f, "stored data can't be read: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for FeedError {}
+
+//same shape as FeedError::body, for errors that aren't about a feed like bad parameters
+pub fn error_body(code: &str, message: &str) -> Value {
+    json!({ "error": { "code": code, "message": message } })
+}
+
+//the lookups from_redis makes to tell a missing key from one holding the wrong thing
+pub trait FeedKeys {
+    //both keys a snapshot is read from
+    fn category_exists(&mut self, feed: &str, category: &str) -> RedisResult<bool>;
+    //ingesters add feeds to the catalog, older ones only set gtfsrtexists
+    fn feed_exists(&mut self, feed: &str) -> RedisResult<bool>;
+}
+
+impl FeedKeys for Connection {
+    fn category_exists(&mut self, feed: &str, category: &str) -> RedisResult<bool> {
+        let (time, bytes): (bool, bool) = redis::pipe()
+            .exists(format!("gtfsrttime|{}|{}", feed, category))
+            .exists(format!("gtfsrt|{}|{}", feed, category))
+            .query(self)?;
+        Ok(time && bytes)
+    }
+
+    fn feed_exists(&mut self, feed: &str) -> RedisResult<bool> {
+        let (score, exists): (Option<f64>, bool) = redis::pipe()
+            .zscore(FEEDS_KEY, feed)
+            .exists(format!("gtfsrtexists|{}", feed))
+            .query(self)?;
+        Ok(score.is_some() || exists)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    //keys that exist, or a connection that fails on every lookup when None
+    struct Keys {
+        categories: Option<Vec<(&'static str, &'static str)>>,
+        feeds: Vec<&'static str>,
+    }
+
+    impl Keys {
+        fn down() -> RedisError {
+            RedisError::from(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))
+        }
+    }
+
+    impl FeedKeys for Keys {
+        fn category_exists(&mut self, feed: &str, category: &str) -> RedisResult<bool> {
+            match &self.categories {
+                Some(categories) => Ok(categories.contains(&(feed, category))),
+                None => Err(Keys::down()),
+            }
+        }
+
+        fn feed_exists(&mut self, feed: &str) -> RedisResult<bool> {
+            match &self.categories {
+                Some(_) => Ok(self.feeds.contains(&feed)),
+                None => Err(Keys::down()),
+            }
+        }
+    }
+
+    fn nil() -> RedisError {
+        RedisError::from((ErrorKind::TypeError, "response was nil"))
+    }
+
+    fn stored() -> Keys {
+        Keys {
+            categories: Some(vec![("f", "vehicles")]),
+            feeds: vec!["f"],
+        }
+    }
+
+    #[test]
+    fn missing_keys_are_not_found() {
+        let error = FeedError::from_redis(&mut stored(), "g", "vehicles", nil());
+        assert_eq!(error.code(), "unknown_feed");
+        assert_eq!(error.status(), 404);
+        let error = FeedError::from_redis(&mut stored(), "f", "alerts", nil());
+        assert_eq!(error.code(), "unknown_category");
+        assert_eq!(error.status(), 404);
+    }
+
+    #[test]
+    fn existing_key_of_the_wrong_type_is_internal() {
+        let error = FeedError::from_redis(&mut stored(), "f", "vehicles", nil());
+        assert_eq!(error.code(), "internal");
+        assert_eq!(error.status(), 500);
+    }
+
+    #[test]
+    fn connection_failures_are_backend_unavailable() {
+        let error = FeedError::from_redis(&mut stored(), "f", "vehicles", Keys::down());
+        assert_eq!(error.code(), "backend_unavailable");
+        assert_eq!(error.status(), 503);
+        //failing while checking which keys exist too
+        let mut down = Keys {
+            categories: None,
+            feeds: Vec::new(),
+        };
+        let error = FeedError::from_redis(&mut down, "f", "vehicles", nil());
+        assert_eq!(error.code(), "backend_unavailable");
+    }
+
+    #[test]
+    fn body_carries_the_code_fields_and_message() {
+        let error = FeedError::UnknownCategory {
+            feed: String::from("f"),
+            category: String::from("alerts"),
+        };
+        assert_eq!(
+            error.body(),
+            json!({
+                "error": {
+                    "code": "unknown_category",
+                    "feed": "f",
+                    "category": "alerts",
+                    "message": "f has no alerts data",
+                }
+            })
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use gtfs_rt::{Alert, EntitySelector, FeedEntity, FeedMessage, TripUpdate, VehiclePosition};
+use qstring::QString;
+
+use crate::geo::{Area, VehicleIndex};
+
+//entity filters taken from the query string
+//each parameter accepts several values, either comma separated or repeated
+//an entity is kept when it matches every filter given, and any of the values for each filter
+//a filter only applies to entity types that carry that field, so vehicle_id doesn't affect alerts
+//agency_id is only accepted for alerts, vehicles and trip updates don't say which agency runs them
+//with category=all it filters the alerts and leaves the rest alone
+//an area only keeps entities with a vehicle position inside it, so it's only accepted for vehicles
+#[derive(Debug, Default, Clone)]
+pub struct EntityFilter {
+    pub route_ids: Vec<String>,
+    pub trip_ids: Vec<String>,
+    pub stop_ids: Vec<String>,
+    pub vehicle_ids: Vec<String>,
+    pub direction_ids: Vec<u32>,
+    pub agency_ids: Vec<String>,
+    pub area: Option<Area>,
+}
+
+fn values(qs: &QString, names: &[&str]) -> Vec<String> {
+    qs.to_pairs()
+        .into_iter()
+        .filter(|(name, _)| names.contains(name))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_string())
+        .collect()
+}
+
+fn check_str(wanted: &[String], value: Option<&str>) -> bool {
+    wanted.is_empty() || value.map_or(false, |value| wanted.iter().any(|w| w == value))
+}
+
+fn check_u32(wanted: &[u32], value: Option<u32>) -> bool {
+    wanted.is_empty() || value.map_or(false, |value| wanted.contains(&value))
+}
+
+impl EntityFilter {
+    pub fn from_query(qs: &QString, category: &str) -> Result<EntityFilter, String> {
+        let mut direction_ids = Vec::new();
+        for direction in values(qs, &["direction_id"]) {
+            match direction.as_str() {
+                "0" => direction_ids.push(0),
+                "1" => direction_ids.push(1),
+                _ => return Err(format!("direction_id must be 0 or 1, not {}", direction)),
+            }
+        }
+        let agency_ids = values(qs, &["agency_id"]);
+        if !agency_ids.is_empty() && category != "alerts" && category != "all" {
+            return Err(String::from("agency_id can only filter alerts"));
+        }
+        let area = Area::from_query(qs)?;
+        if area.is_some() && category != "vehicles" {
+            return Err(String::from(
+                "bbox and lat, lon and radius can only filter vehicles",
+            ));
+        }
+        Ok(EntityFilter {
+            //route is the old name used by /gtfsrtasjson
+            route_ids: values(qs, &["route_id", "route"]),
+            trip_ids: values(qs, &["trip_id"]),
+            stop_ids: values(qs, &["stop_id"]),
+            vehicle_ids: values(qs, &["vehicle_id"]),
+            direction_ids,
+            agency_ids,
+            area,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.route_ids.is_empty()
+            && self.trip_ids.is_empty()
+            && self.stop_ids.is_empty()
+            && self.vehicle_ids.is_empty()
+            && self.direction_ids.is_empty()
+            && self.agency_ids.is_empty()
+            && self.area.is_none()
+    }
+
+    pub fn matches_vehicle(&self, vehicle: &VehiclePosition) -> bool {
+        if let Some(area) = &self.area {
+            match &vehicle.position {
+                Some(position) => {
+                    if !area.contains(position.latitude as f64, position.longitude as f64) {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+        let trip = vehicle.trip.as_ref();
+        check_str(&self.route_ids, trip.and_then(|trip| trip.route_id.as_deref()))
+            && check_str(&self.trip_ids, trip.and_then(|trip| trip.trip_id.as_deref()))
+            && check_u32(&self.direction_ids, trip.and_then(|trip| trip.direction_id))
+            && check_str(&self.stop_ids, vehicle.stop_id.as_deref())
+            && check_str(
+                &self.vehicle_ids,
+                vehicle.vehicle.as_ref().and_then(|v| v.id.as_deref()),
+            )
+    }
+
+    pub fn matches_trip_update(&self, trip_update: &TripUpdate) -> bool {
+        let trip = &trip_update.trip;
+        check_str(&self.route_ids, trip.route_id.as_deref())
+            && check_str(&self.trip_ids, trip.trip_id.as_deref())
+            && check_u32(&self.direction_ids, trip.direction_id)
+            && check_str(
+                &self.vehicle_ids,
+                trip_update.vehicle.as_ref().and_then(|v| v.id.as_deref()),
+            )
+            && (self.stop_ids.is_empty()
+                || trip_update
+                    .stop_time_update
+                    .iter()
+                    .any(|stop_time| check_str(&self.stop_ids, stop_time.stop_id.as_deref())))
+    }
+
+    pub fn matches_selector(&self, selector: &EntitySelector) -> bool {
+        let trip = selector.trip.as_ref();
+        let route_id = selector
+            .route_id
+            .as_deref()
+            .or_else(|| trip.and_then(|trip| trip.route_id.as_deref()));
+        check_str(&self.route_ids, route_id)
+            && check_str(&self.trip_ids, trip.and_then(|trip| trip.trip_id.as_deref()))
+            && check_u32(&self.direction_ids, trip.and_then(|trip| trip.direction_id))
+            && check_str(&self.stop_ids, selector.stop_id.as_deref())
+            && check_str(&self.agency_ids, selector.agency_id.as_deref())
+    }
+
+    //the alert with only the informed entities that match, None if nothing matched
+    pub fn filter_alert(&self, alert: &Alert) -> Option<Alert> {
+        let informed_entity: Vec<EntitySelector> = alert
+            .informed_entity
+            .iter()
+            .filter(|selector| self.matches_selector(selector))
+            .cloned()
+            .collect();
+        if informed_entity.is_empty() {
+            return None;
+        }
+        let mut alert = alert.clone();
+        alert.informed_entity = informed_entity;
+        Some(alert)
+    }
+
+    //the entity with the parts that don't match removed, None if nothing is left
+    pub fn filter_entity(&self, entity: &FeedEntity) -> Option<FeedEntity> {
+        let mut filtered_entity = FeedEntity::default();
+        filtered_entity.id = entity.id.clone();
+        filtered_entity.is_deleted = entity.is_deleted;
+        filtered_entity.vehicle = entity
+            .vehicle
+            .as_ref()
+            .filter(|vehicle| self.matches_vehicle(vehicle))
+            .cloned();
+        filtered_entity.trip_update = entity
+            .trip_update
+            .as_ref()
+            .filter(|trip_update| self.matches_trip_update(trip_update))
+            .cloned();
+        filtered_entity.alert = entity
+            .alert
+            .as_ref()
+            .and_then(|alert| self.filter_alert(alert));
+
+        if self.area.is_some() && filtered_entity.vehicle.is_none() {
+            return None;
+        }
+
+        if filtered_entity.vehicle.is_some()
+            || filtered_entity.trip_update.is_some()
+            || filtered_entity.alert.is_some()
+        {
+            Some(filtered_entity)
+        } else {
+            None
+        }
+    }
+
+    pub fn apply(&self, message: &FeedMessage) -> FeedMessage {
+        let mut filtered_message = FeedMessage::default();
+        filtered_message.header = message.header.clone();
+        filtered_message.entity = message
+            .entity
+            .iter()
+            .filter_map(|entity| self.filter_entity(entity))
+            .collect();
+        filtered_message
+    }
+
+    //same as apply, but only looks at the entities the index puts inside the area
+    pub fn apply_indexed(&self, message: &FeedMessage, index: &VehicleIndex) -> FeedMessage {
+        let area = match &self.area {
+            Some(area) => area,
+            None => return self.apply(message),
+        };
+        let mut filtered_message = FeedMessage::default();
+        filtered_message.header = message.header.clone();
+        filtered_message.entity = index
+            .entities_in(area)
+            .into_iter()
+            .filter_map(|i| self.filter_entity(&message.entity[i]))
+            .collect();
+        filtered_message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gtfs_rt::{trip_update::StopTimeUpdate, TripDescriptor, VehicleDescriptor};
+
+    use super::*;
+
+    fn filter(query: &str, category: &str) -> EntityFilter {
+        EntityFilter::from_query(&QString::from(query), category).unwrap()
+    }
+
+    fn trip(route_id: &str, trip_id: &str, direction_id: u32) -> TripDescriptor {
+        TripDescriptor {
+            route_id: Some(route_id.to_string()),
+            trip_id: Some(trip_id.to_string()),
+            direction_id: Some(direction_id),
+            ..TripDescriptor::default()
+        }
+    }
+
+    fn vehicle(trip: TripDescriptor, stop_id: &str, vehicle_id: &str) -> VehiclePosition {
+        VehiclePosition {
+            trip: Some(trip),
+            stop_id: Some(stop_id.to_string()),
+            vehicle: Some(VehicleDescriptor {
+                id: Some(vehicle_id.to_string()),
+                ..VehicleDescriptor::default()
+            }),
+            ..VehiclePosition::default()
+        }
+    }
+
+    fn trip_update(trip: TripDescriptor, stop_ids: &[&str]) -> TripUpdate {
+        TripUpdate {
+            trip,
+            stop_time_update: stop_ids
+                .iter()
+                .map(|stop_id| StopTimeUpdate {
+                    stop_id: Some(stop_id.to_string()),
+                    ..StopTimeUpdate::default()
+                })
+                .collect(),
+            ..TripUpdate::default()
+        }
+    }
+
+    fn selector(
+        agency_id: Option<&str>,
+        route_id: Option<&str>,
+        stop_id: Option<&str>,
+    ) -> EntitySelector {
+        EntitySelector {
+            agency_id: agency_id.map(|agency_id| agency_id.to_string()),
+            route_id: route_id.map(|route_id| route_id.to_string()),
+            stop_id: stop_id.map(|stop_id| stop_id.to_string()),
+            ..EntitySelector::default()
+        }
+    }
+
+    #[test]
+    fn vehicles_match_route_trip_stop_and_direction() {
+        let vehicle = vehicle(trip("red", "t1", 0), "s1", "v1");
+        assert!(filter("route_id=red", "vehicles").matches_vehicle(&vehicle));
+        assert!(filter("route=blue,red", "vehicles").matches_vehicle(&vehicle));
+        assert!(filter("trip_id=t1&stop_id=s1", "vehicles").matches_vehicle(&vehicle));
+        assert!(filter("direction_id=0&vehicle_id=v1", "vehicles").matches_vehicle(&vehicle));
+        assert!(!filter("route_id=red&direction_id=1", "vehicles").matches_vehicle(&vehicle));
+        assert!(!filter("stop_id=s2", "vehicles").matches_vehicle(&vehicle));
+        assert!(!filter("route_id=red", "vehicles").matches_vehicle(&VehiclePosition::default()));
+    }
+
+    #[test]
+    fn trip_updates_match_any_of_their_stops() {
+        let trip_update = trip_update(trip("red", "t1", 1), &["s1", "s2"]);
+        assert!(filter("stop_id=s2", "trips").matches_trip_update(&trip_update));
+        assert!(filter("route_id=red&trip_id=t1&direction_id=1", "trips")
+            .matches_trip_update(&trip_update));
+        assert!(!filter("stop_id=s3", "trips").matches_trip_update(&trip_update));
+        assert!(!filter("trip_id=t2", "trips").matches_trip_update(&trip_update));
+        assert!(!filter("direction_id=0", "trips").matches_trip_update(&trip_update));
+    }
+
+    #[test]
+    fn alerts_keep_only_matching_informed_entities() {
+        let alert = Alert {
+            informed_entity: vec![
+                selector(Some("bart"), Some("red"), None),
+                selector(Some("muni"), None, Some("s1")),
+                EntitySelector {
+                    trip: Some(trip("blue", "t1", 0)),
+                    ..EntitySelector::default()
+                },
+            ],
+            ..Alert::default()
+        };
+        let kept = |filter: EntityFilter| {
+            filter
+                .filter_alert(&alert)
+                .map(|alert| alert.informed_entity.len())
+        };
+        assert_eq!(kept(filter("route_id=red", "alerts")), Some(1));
+        //the route of a trip selector counts too
+        assert_eq!(kept(filter("route_id=red,blue", "alerts")), Some(2));
+        assert_eq!(kept(filter("stop_id=s1", "alerts")), Some(1));
+        assert_eq!(kept(filter("trip_id=t1&direction_id=0", "alerts")), Some(1));
+        assert_eq!(kept(filter("agency_id=bart,muni", "alerts")), Some(2));
+        assert_eq!(kept(filter("agency_id=caltrain", "alerts")), None);
+    }
+
+    #[test]
+    fn agency_id_only_filters_alerts() {
+        let query = QString::from("agency_id=bart");
+        assert!(EntityFilter::from_query(&query, "vehicles").is_err());
+        assert!(EntityFilter::from_query(&query, "trips").is_err());
+        assert!(EntityFilter::from_query(&query, "alerts").is_ok());
+
+        let filter = filter("agency_id=bart", "all");
+        let mut vehicle_entity = FeedEntity::default();
+        vehicle_entity.vehicle = Some(vehicle(trip("red", "t1", 0), "s1", "v1"));
+        assert!(filter.filter_entity(&vehicle_entity).is_some());
+        let mut alert_entity = FeedEntity::default();
+        alert_entity.alert = Some(Alert {
+            informed_entity: vec![selector(Some("muni"), None, None)],
+            ..Alert::default()
+        });
+        assert!(filter.filter_entity(&alert_entity).is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_directions() {
+        assert!(EntityFilter::from_query(&QString::from("direction_id=2"), "vehicles").is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use std::path::Path;
+
+use gtfs_rt::FeedMessage;
+use prost::Message;
+use protobuf::descriptor::FileDescriptorSet;
+use protobuf::reflect::{FileDescriptor, MessageDescriptor};
+use protobuf::Message as _;
+use qstring::QString;
+
+//output formats for feed endpoints, chosen with format=
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Protobuf,
+    //the prost structs through serde, what /gtfsrtasjson has always returned
+    Json,
+    //canonical proto3 json mapping, enum names, camelCase, 64 bit ints as strings
+    ProtoJson,
+    //protobuf text format
+    Text,
+    //rust debug output, the old raw=true
+    Debug,
+    //vehicle positions as a FeatureCollection of points, only for vehicles
+    GeoJson,
+    //one row per vehicle, or one row per stop time update for trips
+    Csv,
+}
+
+impl OutputFormat {
+    pub fn from_query(qs: &QString, default: OutputFormat) -> Result<OutputFormat, String> {
+        match qs.get("format") {
+            Some("protobuf") | Some("pb") => Ok(OutputFormat::Protobuf),
+            Some("json") => Ok(OutputFormat::Json),
+            Some("protojson") => Ok(OutputFormat::ProtoJson),
+            Some("text") => Ok(OutputFormat::Text),
+            Some("debug") => Ok(OutputFormat::Debug),
+            Some("geojson") => Ok(OutputFormat::GeoJson),
+            Some("csv") => Ok(OutputFormat::Csv),
+            Some(other) => Err(format!("unknown format {}", other)),
+            None => Ok(default),
+        }
+    }
+}
+
+//gtfs-realtime.proto compiled by build.rs, so protojson and text don't depend on the working directory
+const GTFS_REALTIME_DESCRIPTOR: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/gtfs-realtime.desc"));
+
+//the descriptor protobuf's json mapping and text format use to walk the messages
+pub struct FeedDescriptor {
+    feed_message: MessageDescriptor,
+}
+
+impl FeedDescriptor {
+    //the gtfs-realtime.proto built into the binary
+    pub fn embedded() -> anyhow::Result<FeedDescriptor> {
+        let set = FileDescriptorSet::parse_from_bytes(GTFS_REALTIME_DESCRIPTOR)?;
+        FeedDescriptor::from_set(set, "the embedded gtfs-realtime.proto")
+    }
+
+    //another .proto given with --proto, e.g. one with extensions
+    pub fn load(proto_path: &str) -> anyhow::Result<FeedDescriptor> {
+        let path = Path::new(proto_path);
+        let include = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let set = protobuf_parse::Parser::new()
+            .pure()
+            .include(include)
+            .input(path)
+            .file_descriptor_set()?;
+        FeedDescriptor::from_set(set, proto_path)
+    }
+
+    fn from_set(set: FileDescriptorSet, source: &str) -> anyhow::Result<FeedDescriptor> {
+        let files = FileDescriptor::new_dynamic_fds(set.file, &[])?;
+        let feed_message = files
+            .iter()
+            .find_map(|file| file.message_by_package_relative_name("FeedMessage"))
+            .ok_or_else(|| anyhow::anyhow!("FeedMessage not found in {}", source))?;
+        Ok(FeedDescriptor { feed_message })
+    }
+
+    fn dynamic(&self, message: &FeedMessage) -> Result<Box<dyn protobuf::MessageDyn>, String> {
+        self.feed_message
+            .parse_from_bytes(&message.encode_to_vec())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn to_proto_json(&self, message: &FeedMessage) -> Result<String, String> {
+        let dynamic = self.dynamic(message)?;
+        protobuf_json_mapping::print_to_string(&*dynamic).map_err(|e| e.to_string())
+    }
+
+    pub fn to_text(&self, message: &FeedMessage) -> Result<String, String> {
+        let dynamic = self.dynamic(message)?;
+        Ok(protobuf::text_format::print_to_string_pretty(&*dynamic))
+    }
+}
+
+//the header is written even when there are no rows, rows are written in field order
+//so the columns and fields have to stay in the same order, and new ones only ever go at the end
+//the tests check the columns against the names serde gives the row fields
+const VEHICLE_COLUMNS: [&str; 16] = [
+    "entity_id",
+    "vehicle_id",
+    "label",
+    "trip_id",
+    "route_id",
+    "direction_id",
+    "start_date",
+    "start_time",
+    "latitude",
+    "longitude",
+    "bearing",
+    "speed",
+    "stop_id",
+    "current_stop_sequence",
+    "current_status",
+    "timestamp",
+];
+
+const STOP_TIME_COLUMNS: [&str; 14] = [
+    "entity_id",
+    "trip_id",
+    "route_id",
+    "direction_id",
+    "start_date",
+    "start_time",
+    "vehicle_id",
+    "stop_sequence",
+    "stop_id",
+    "arrival_delay",
+    "arrival_time",
+    "departure_delay",
+    "departure_time",
+    "schedule_relationship",
+];
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Default))]
+struct VehicleRow<'a> {
+    entity_id: &'a str,
+    vehicle_id: Option<&'a str>,
+    label: Option<&'a str>,
+    trip_id: Option<&'a str>,
+    route_id: Option<&'a str>,
+    direction_id: Option<u32>,
+    start_date: Option<&'a str>,
+    start_time: Option<&'a str>,
+    latitude: Option<f32>,
+    longitude: Option<f32>,
+    bearing: Option<f32>,
+    speed: Option<f32>,
+    stop_id: Option<&'a str>,
+    current_stop_sequence: Option<u32>,
+    current_status: Option<i32>,
+    timestamp: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Default))]
+struct StopTimeRow<'a> {
+    entity_id: &'a str,
+    trip_id: Option<&'a str>,
+    route_id: Option<&'a str>,
+    direction_id: Option<u32>,
+    start_date: Option<&'a str>,
+    start_time: Option<&'a str>,
+    vehicle_id: Option<&'a str>,
+    stop_sequence: Option<u32>,
+    stop_id: Option<&'a str>,
+    arrival_delay: Option<i32>,
+    arrival_time: Option<i64>,
+    departure_delay: Option<i32>,
+    departure_time: Option<i64>,
+    schedule_relationship: Option<i32>,
+}
+
+fn csv_writer(columns: &[&str]) -> Result<csv::Writer<Vec<u8>>, String> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    writer.write_record(columns).map_err(|e| e.to_string())?;
+    Ok(writer)
+}
+
+fn finish_csv(writer: csv::Writer<Vec<u8>>) -> Result<String, String> {
+    let bytes = writer.into_inner().map_err(|e| e.to_string())?;
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
+
+pub fn vehicles_csv(message: &FeedMessage) -> Result<String, String> {
+    let mut writer = csv_writer(&VEHICLE_COLUMNS)?;
+    for entity in message.entity.iter() {
+        let vehicle = match entity.vehicle.as_ref() {
+            Some(vehicle) => vehicle,
+            None => continue,
+        };
+        let descriptor = vehicle.vehicle.as_ref();
+        let trip = vehicle.trip.as_ref();
+        let position = vehicle.position.as_ref();
+        writer
+            .serialize(VehicleRow {
+                entity_id: &entity.id,
+                vehicle_id: descriptor.and_then(|d| d.id.as_deref()),
+                label: descriptor.and_then(|d| d.label.as_deref()),
+                trip_id: trip.and_then(|trip| trip.trip_id.as_deref()),
+                route_id: trip.and_then(|trip| trip.route_id.as_deref()),
+                direction_id: trip.and_then(|trip| trip.direction_id),
+                start_date: trip.and_then(|trip| trip.start_date.as_deref()),
+                start_time: trip.and_then(|trip| trip.start_time.as_deref()),
+                latitude: position.map(|position| position.latitude),
+                longitude: position.map(|position| position.longitude),
+                bearing: position.and_then(|position| position.bearing),
+                speed: position.and_then(|position| position.speed),
+                stop_id: vehicle.stop_id.as_deref(),
+                current_stop_sequence: vehicle.current_stop_sequence,
+                current_status: vehicle.current_status,
+                timestamp: vehicle.timestamp,
+            })
+            .map_err(|e| e.to_string())?;
+    }
+    finish_csv(writer)
+}
+
+//trip updates without stop time updates have no rows
+pub fn trip_updates_csv(message: &FeedMessage) -> Result<String, String> {
+    let mut writer = csv_writer(&STOP_TIME_COLUMNS)?;
+    for entity in message.entity.iter() {
+        let trip_update = match entity.trip_update.as_ref() {
+            Some(trip_update) => trip_update,
+            None => continue,
+        };
+        let trip = &trip_update.trip;
+        for stop_time in trip_update.stop_time_update.iter() {
+            let arrival = stop_time.arrival.as_ref();
+            let departure = stop_time.departure.as_ref();
+            writer
+                .serialize(StopTimeRow {
+                    entity_id: &entity.id,
+                    trip_id: trip.trip_id.as_deref(),
+                    route_id: trip.route_id.as_deref(),
+                    direction_id: trip.direction_id,
+                    start_date: trip.start_date.as_deref(),
+                    start_time: trip.start_time.as_deref(),
+                    vehicle_id: trip_update.vehicle.as_ref().and_then(|v| v.id.as_deref()),
+                    stop_sequence: stop_time.stop_sequence,
+                    stop_id: stop_time.stop_id.as_deref(),
+                    arrival_delay: arrival.and_then(|event| event.delay),
+                    arrival_time: arrival.and_then(|event| event.time),
+                    departure_delay: departure.and_then(|event| event.delay),
+                    departure_time: departure.and_then(|event| event.time),
+                    schedule_relationship: stop_time.schedule_relationship,
+                })
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    finish_csv(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use gtfs_rt::FeedHeader;
+
+    use super::*;
+
+    //the header csv writes itself for a row, which is what the fixed columns have to match
+    fn serde_header<T: serde::Serialize>(row: T) -> String {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.serialize(row).unwrap();
+        let csv = finish_csv(writer).unwrap();
+        csv.lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn csv_columns_match_row_fields() {
+        let vehicles = serde_header(VehicleRow::default());
+        assert_eq!(vehicles, VEHICLE_COLUMNS.join(","));
+        let stop_times = serde_header(StopTimeRow::default());
+        assert_eq!(stop_times, STOP_TIME_COLUMNS.join(","));
+    }
+
+    #[test]
+    fn embedded_descriptor_renders_text() {
+        let descriptor = FeedDescriptor::embedded().unwrap();
+        let mut message = FeedMessage::default();
+        message.header = FeedHeader {
+            gtfs_realtime_version: String::from("2.0"),
+            timestamp: Some(1700000000),
+            ..FeedHeader::default()
+        };
+        let text = descriptor.to_text(&message).unwrap();
+        assert!(text.contains("gtfs_realtime_version: \"2.0\""), "{}", text);
+        assert!(text.contains("timestamp: 1700000000"), "{}", text);
+    }
+}
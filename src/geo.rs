@@ -0,0 +1,335 @@
+use gtfs_rt::FeedMessage;
+use qstring::QString;
+use rstar::{primitives::GeomWithData, RTree, AABB};
+use serde_json::{json, Value};
+
+const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+//great circle distance between two points in meters
+pub fn haversine(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let dlat = (lat2 - lat1).to_radians();
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
+}
+
+//area requested by a map client, either bbox=min_lon,min_lat,max_lon,max_lat
+//or lat=&lon=&radius= with the radius in meters
+//a bbox with min_lon above max_lon crosses the antimeridian
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Area {
+    BoundingBox {
+        min_lon: f64,
+        min_lat: f64,
+        max_lon: f64,
+        max_lat: f64,
+    },
+    Radius {
+        lat: f64,
+        lon: f64,
+        meters: f64,
+    },
+}
+
+pub fn parse_number(qs: &QString, name: &str) -> Result<Option<f64>, String> {
+    match qs.get(name) {
+        Some(value) => match value.trim().parse::<f64>() {
+            Ok(number) if number.is_finite() => Ok(Some(number)),
+            _ => Err(format!("{} must be a number", name)),
+        },
+        None => Ok(None),
+    }
+}
+
+impl Area {
+    pub fn from_query(qs: &QString) -> Result<Option<Area>, String> {
+        if let Some(bbox) = qs.get("bbox") {
+            let corners = bbox
+                .split(',')
+                .map(|corner| corner.trim().parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>()
+                .map_err(|_| String::from("bbox must be min_lon,min_lat,max_lon,max_lat"))?;
+            if corners.len() != 4 || corners.iter().any(|corner| !corner.is_finite()) {
+                return Err(String::from("bbox must be min_lon,min_lat,max_lon,max_lat"));
+            }
+            if corners[1] > corners[3] {
+                return Err(String::from("bbox min_lat can't be above max_lat"));
+            }
+            return Ok(Some(Area::BoundingBox {
+                min_lon: corners[0],
+                min_lat: corners[1],
+                max_lon: corners[2],
+                max_lat: corners[3],
+            }));
+        }
+
+        let lat = parse_number(qs, "lat")?;
+        let lon = parse_number(qs, "lon")?;
+        let radius = parse_number(qs, "radius")?;
+        match (lat, lon, radius) {
+            (None, None, None) => Ok(None),
+            (Some(lat), Some(lon), Some(meters)) if meters > 0.0 => {
+                Ok(Some(Area::Radius { lat, lon, meters }))
+            }
+            _ => Err(String::from(
+                "lat, lon and a positive radius in meters must be given together",
+            )),
+        }
+    }
+
+    //rectangles in [lon, lat] that together cover the whole area
+    //a bbox crossing the antimeridian needs one on each side of it
+    pub fn envelopes(&self) -> Vec<AABB<[f64; 2]>> {
+        match *self {
+            Area::BoundingBox {
+                min_lon,
+                min_lat,
+                max_lon,
+                max_lat,
+            } if min_lon > max_lon => vec![
+                AABB::from_corners([min_lon, min_lat], [180.0, max_lat]),
+                AABB::from_corners([-180.0, min_lat], [max_lon, max_lat]),
+            ],
+            Area::BoundingBox {
+                min_lon,
+                min_lat,
+                max_lon,
+                max_lat,
+            } => vec![AABB::from_corners([min_lon, min_lat], [max_lon, max_lat])],
+            Area::Radius { lat, lon, meters } => {
+                let dlat = meters / METERS_PER_DEGREE;
+                let dlon = meters / (METERS_PER_DEGREE * lat.to_radians().cos().max(0.01));
+                vec![AABB::from_corners(
+                    [lon - dlon, lat - dlat],
+                    [lon + dlon, lat + dlat],
+                )]
+            }
+        }
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        match *self {
+            Area::BoundingBox {
+                min_lon,
+                min_lat,
+                max_lon,
+                max_lat,
+            } => {
+                let in_lon = if min_lon > max_lon {
+                    lon >= min_lon || lon <= max_lon
+                } else {
+                    lon >= min_lon && lon <= max_lon
+                };
+                in_lon && lat >= min_lat && lat <= max_lat
+            }
+            Area::Radius {
+                lat: center_lat,
+                lon: center_lon,
+                meters,
+            } => haversine(center_lat, center_lon, lat, lon) <= meters,
+        }
+    }
+}
+
+//spatial index of the vehicle positions in one snapshot, pointing back at entity indexes
+pub struct VehicleIndex {
+    tree: RTree<GeomWithData<[f64; 2], usize>>,
+}
+
+impl VehicleIndex {
+    pub fn new(message: &FeedMessage) -> VehicleIndex {
+        let points = message
+            .entity
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entity)| {
+                let position = entity.vehicle.as_ref()?.position.as_ref()?;
+                Some(GeomWithData::new(
+                    [position.longitude as f64, position.latitude as f64],
+                    i,
+                ))
+            })
+            .collect();
+        VehicleIndex {
+            tree: RTree::bulk_load(points),
+        }
+    }
+
+    //indexes of entities whose vehicle is inside a [lon, lat] rectangle, in no particular order
+    pub fn entities_in_envelope(&self, envelope: &AABB<[f64; 2]>) -> Vec<usize> {
+        self.tree
+            .locate_in_envelope(envelope)
+            .map(|point| point.data)
+            .collect()
+    }
+
+    //indexes of entities whose vehicle is inside the area, in feed order
+    pub fn entities_in(&self, area: &Area) -> Vec<usize> {
+        let mut entities: Vec<usize> = area
+            .envelopes()
+            .iter()
+            .flat_map(|envelope| self.tree.locate_in_envelope(envelope))
+            .filter(|point| area.contains(point.geom()[1], point.geom()[0]))
+            .map(|point| point.data)
+            .collect();
+        entities.sort_unstable();
+        entities.dedup();
+        entities
+    }
+}
+
+//vehicle positions as a geojson FeatureCollection of points, vehicles without a position are left out
+pub fn vehicles_geojson(message: &FeedMessage) -> Value {
+    let features: Vec<Value> = message
+        .entity
+        .iter()
+        .filter_map(|entity| {
+            let vehicle = entity.vehicle.as_ref()?;
+            let position = vehicle.position.as_ref()?;
+            let descriptor = vehicle.vehicle.as_ref();
+            let trip = vehicle.trip.as_ref();
+            Some(json!({
+                "type": "Feature",
+                "id": entity.id,
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [position.longitude, position.latitude],
+                },
+                "properties": {
+                    "vehicle_id": descriptor.and_then(|d| d.id.as_deref()),
+                    "label": descriptor.and_then(|d| d.label.as_deref()),
+                    "trip_id": trip.and_then(|trip| trip.trip_id.as_deref()),
+                    "route_id": trip.and_then(|trip| trip.route_id.as_deref()),
+                    "direction_id": trip.and_then(|trip| trip.direction_id),
+                    "bearing": position.bearing,
+                    "speed": position.speed,
+                    "stop_id": vehicle.stop_id.as_deref(),
+                    "timestamp": vehicle.timestamp,
+                },
+            }))
+        })
+        .collect();
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use gtfs_rt::{FeedEntity, Position, VehiclePosition};
+
+    use super::*;
+
+    fn vehicle(id: &str, lon: f32, lat: f32) -> FeedEntity {
+        let mut entity = FeedEntity::default();
+        entity.id = id.to_string();
+        entity.vehicle = Some(VehiclePosition {
+            position: Some(Position {
+                latitude: lat,
+                longitude: lon,
+                ..Position::default()
+            }),
+            ..VehiclePosition::default()
+        });
+        entity
+    }
+
+    fn area(query: &str) -> Result<Option<Area>, String> {
+        Area::from_query(&QString::from(query))
+    }
+
+    #[test]
+    fn parses_bbox_and_radius() {
+        assert_eq!(area(""), Ok(None));
+        assert_eq!(
+            area("bbox=-122.5, 37.7,-122.3,37.9"),
+            Ok(Some(Area::BoundingBox {
+                min_lon: -122.5,
+                min_lat: 37.7,
+                max_lon: -122.3,
+                max_lat: 37.9,
+            }))
+        );
+        assert_eq!(
+            area("lat=37.8&lon=-122.4&radius=500"),
+            Ok(Some(Area::Radius {
+                lat: 37.8,
+                lon: -122.4,
+                meters: 500.0,
+            }))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_areas() {
+        assert!(area("bbox=1,2,3").is_err());
+        assert!(area("bbox=1,2,3,x").is_err());
+        assert!(area("bbox=1,2,3,inf").is_err());
+        assert!(area("bbox=1,3,2,2").is_err());
+        assert!(area("lat=37.8&lon=-122.4").is_err());
+        assert!(area("lat=37.8&lon=-122.4&radius=0").is_err());
+        assert!(area("lat=north&lon=-122.4&radius=10").is_err());
+    }
+
+    #[test]
+    fn bbox_across_the_antimeridian_keeps_its_corners() {
+        let area = area("bbox=170,-20,-170,-10").unwrap().unwrap();
+        assert_eq!(
+            area,
+            Area::BoundingBox {
+                min_lon: 170.0,
+                min_lat: -20.0,
+                max_lon: -170.0,
+                max_lat: -10.0,
+            }
+        );
+        assert!(area.contains(-15.0, 175.0));
+        assert!(area.contains(-15.0, -175.0));
+        assert!(!area.contains(-15.0, 0.0));
+        assert!(!area.contains(-25.0, 175.0));
+        assert_eq!(area.envelopes().len(), 2);
+    }
+
+    #[test]
+    fn contains_checks_bbox_edges_and_radius_distance() {
+        let bbox = area("bbox=0,0,1,1").unwrap().unwrap();
+        assert!(bbox.contains(0.0, 0.0));
+        assert!(bbox.contains(1.0, 1.0));
+        assert!(!bbox.contains(1.1, 0.5));
+
+        //a degree of latitude is about 111km
+        let radius = area("lat=0&lon=0&radius=1000").unwrap().unwrap();
+        assert!(radius.contains(0.005, 0.0));
+        assert!(!radius.contains(0.01, 0.0));
+        assert!((haversine(0.0, 0.0, 1.0, 0.0) - 111_195.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn index_finds_the_same_vehicles_as_contains() {
+        let mut message = FeedMessage::default();
+        message.entity = vec![
+            vehicle("east", 179.5, -15.0),
+            vehicle("inside", 0.5, 0.5),
+            vehicle("west", -179.5, -15.0),
+            vehicle("corner", 0.65, 0.65),
+            FeedEntity::default(),
+        ];
+        let index = VehicleIndex::new(&message);
+
+        let found = |query: &str| -> Vec<&str> {
+            let area = area(query).unwrap().unwrap();
+            index
+                .entities_in(&area)
+                .into_iter()
+                .map(|i| message.entity[i].id.as_str())
+                .collect()
+        };
+        assert_eq!(found("bbox=0,0,1,1"), vec!["inside", "corner"]);
+        assert_eq!(found("bbox=170,-20,-170,-10"), vec!["east", "west"]);
+        //the corner vehicle is inside the envelope of the circle but about 23km from its center
+        assert_eq!(found("lat=0.5&lon=0.5&radius=20000"), vec!["inside"]);
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    sync::{Arc, Mutex},
+};
+
+use redis::{Connection, RedisResult};
+
+use crate::{
+    catalog::{self, CatalogQuery, FeedStatus, CATEGORIES},
+    FailedPayload,
+};
+
+//without an override, a feed is degraded after missing this many fetches and dead after this many
+const DEGRADED_AFTER_INTERVALS: f64 = 3.0;
+const DEAD_AFTER_INTERVALS: f64 = 20.0;
+//for feeds that never said how often they're fetched
+const DEFAULT_DEGRADED_AFTER_SECONDS: f64 = 300.0;
+const DEFAULT_DEAD_AFTER_SECONDS: f64 = 1800.0;
+//how long /health answers from its last check instead of scanning every feed again
+pub const HEALTH_CACHE_SECONDS: u64 = 5;
+
+//ordered from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthState {
+    Healthy,
+    Degraded,
+    Dead,
+}
+
+impl HealthState {
+    pub fn from_name(name: &str) -> Option<HealthState> {
+        match name {
+            "healthy" => Some(HealthState::Healthy),
+            "degraded" => Some(HealthState::Degraded),
+            "dead" => Some(HealthState::Dead),
+            _ => None,
+        }
+    }
+}
+
+//seconds without a new snapshot before a feed counts as degraded or dead
+#[derive(Debug, Clone, Copy)]
+pub struct Threshold {
+    pub degraded_after: f64,
+    pub dead_after: f64,
+}
+
+//per feed thresholds, from a csv with feed,degraded_after,dead_after
+//feeds that aren't listed get thresholds based on their fetch interval
+#[derive(Debug, Default, Clone)]
+pub struct HealthThresholds {
+    overrides: HashMap<String, Threshold>,
+}
+
+#[derive(Deserialize)]
+struct ThresholdRecord {
+    feed: String,
+    degraded_after: f64,
+    dead_after: f64,
+}
+
+impl HealthThresholds {
+    pub fn load(path: &str) -> anyhow::Result<HealthThresholds> {
+        let mut reader = csv::Reader::from_reader(BufReader::new(File::open(path)?));
+        let mut overrides = HashMap::new();
+        for record in reader.deserialize::<ThresholdRecord>() {
+            let record = record?;
+            overrides.insert(
+                record.feed,
+                Threshold {
+                    degraded_after: record.degraded_after,
+                    dead_after: record.dead_after,
+                },
+            );
+        }
+        Ok(HealthThresholds { overrides })
+    }
+
+    //also decides when /catalog calls a category stale, so both agree
+    pub fn threshold(&self, feed: &str, fetch_interval: Option<f32>) -> Threshold {
+        if let Some(threshold) = self.overrides.get(feed) {
+            return *threshold;
+        }
+        match fetch_interval {
+            Some(interval) if interval > 0.0 => Threshold {
+                degraded_after: interval as f64 * DEGRADED_AFTER_INTERVALS,
+                dead_after: interval as f64 * DEAD_AFTER_INTERVALS,
+            },
+            _ => Threshold {
+                degraded_after: DEFAULT_DEGRADED_AFTER_SECONDS,
+                dead_after: DEFAULT_DEAD_AFTER_SECONDS,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedHealth {
+    pub feed: String,
+    pub state: HealthState,
+    //seconds since the category that decided the state was fetched, None if it never stored anything
+    pub age: Option<f64>,
+    pub reason: String,
+}
+
+//decides how a feed is doing from its catalog entry and the last failed fetch of each category
+//every category with data or configured in the ingest engine gets a state and the feed gets the worst,
+//so fresh vehicles don't hide trip updates that stopped coming in
+pub fn evaluate(
+    status: &FeedStatus,
+    failed: &[FailedPayload],
+    thresholds: &HealthThresholds,
+) -> FeedHealth {
+    let threshold = thresholds.threshold(&status.feed, status.fetch_interval);
+    let worst = CATEGORIES
+        .iter()
+        .filter(|category| {
+            status.category(category).is_some()
+                || status
+                    .configured
+                    .iter()
+                    .any(|configured| configured == *category)
+        })
+        .map(|category| evaluate_category(status, category, failed, &threshold))
+        //the oldest category wins between ones in the same state
+        .max_by(|a, b| {
+            a.0.cmp(&b.0).then(
+                a.1.unwrap_or(f64::INFINITY)
+                    .total_cmp(&b.1.unwrap_or(f64::INFINITY)),
+            )
+        });
+
+    let (state, age, reason) = worst.unwrap_or((
+        HealthState::Dead,
+        None,
+        String::from("no data has been stored"),
+    ));
+    FeedHealth {
+        feed: status.feed.clone(),
+        state,
+        age,
+        reason,
+    }
+}
+
+fn evaluate_category(
+    status: &FeedStatus,
+    category: &str,
+    failed: &[FailedPayload],
+    threshold: &Threshold,
+) -> (HealthState, Option<f64>, String) {
+    //fetched only moves when a payload decodes and gets inserted, undecodable ones are kept as
+    //failures instead, so a feed serving garbage stays degraded until a good payload comes in
+    let fetched = status.category(category).map(|stored| stored.fetched);
+    let failing = failed.iter().find(|failed| {
+        failed.category == category && fetched.map_or(true, |fetched| failed.time > fetched)
+    });
+
+    let stored = match status.category(category) {
+        Some(stored) => stored,
+        None => {
+            let reason = match failing {
+                Some(failed) => format!("{} fetch failing: {}", category, failed.error),
+                None => format!("no {} data has been stored", category),
+            };
+            return (HealthState::Dead, None, reason);
+        }
+    };
+    if stored.age > threshold.dead_after {
+        return (
+            HealthState::Dead,
+            Some(stored.age),
+            format!("no new {} data for {:.0} seconds", category, stored.age),
+        );
+    }
+    if stored.age > threshold.degraded_after {
+        return (
+            HealthState::Degraded,
+            Some(stored.age),
+            format!("no new {} data for {:.0} seconds", category, stored.age),
+        );
+    }
+    match failing {
+        Some(failed) => (
+            HealthState::Degraded,
+            Some(stored.age),
+            format!("{} fetch failing: {}", category, failed.error),
+        ),
+        None => (HealthState::Healthy, Some(stored.age), String::new()),
+    }
+}
+
+//the last check_all result, so a busy /health doesn't scan redis on every request
+#[derive(Default)]
+pub struct HealthCache {
+    checked: Mutex<Option<(u64, Arc<Vec<FeedHealth>>)>>,
+}
+
+impl HealthCache {
+    pub fn get(&self, now_millis: u64) -> Option<Arc<Vec<FeedHealth>>> {
+        match self.checked.lock().unwrap().as_ref() {
+            Some((checked, feeds))
+                if now_millis.saturating_sub(*checked) < HEALTH_CACHE_SECONDS * 1000 =>
+            {
+                Some(feeds.clone())
+            }
+            _ => None,
+        }
+    }
+
+    //the lock isn't held while checking, two requests missing at once both scan
+    pub fn store(&self, now_millis: u64, feeds: Vec<FeedHealth>) -> Arc<Vec<FeedHealth>> {
+        let feeds = Arc::new(feeds);
+        *self.checked.lock().unwrap() = Some((now_millis, feeds.clone()));
+        feeds
+    }
+}
+
+//the health of every feed in the catalog
+pub fn check_all(
+    con: &mut Connection,
+    thresholds: &HealthThresholds,
+    now_millis: u64,
+) -> RedisResult<Vec<FeedHealth>> {
+    let query = CatalogQuery {
+        limit: usize::MAX,
+        ..CatalogQuery::default()
+    };
+    let feeds = catalog::list(con, &query, thresholds, now_millis)?.feeds;
+
+    let mut pipe = redis::pipe();
+    for status in feeds.iter() {
+        for category in CATEGORIES {
+            pipe.get(format!("gtfsrtfailed|{}|{}", status.feed, category));
+        }
+    }
+    let failed: Vec<Option<String>> = pipe.query(con)?;
+
+    Ok(feeds
+        .iter()
+        .zip(failed.chunks(CATEGORIES.len()))
+        .map(|(status, failed)| {
+            let failed: Vec<FailedPayload> = failed
+                .iter()
+                .flatten()
+                .filter_map(|failed| serde_json::from_str(failed).ok())
+                .collect();
+            evaluate(status, &failed, thresholds)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::CategoryStatus;
+
+    fn status(fetched: u64) -> FeedStatus {
+        FeedStatus {
+            feed: String::from("f"),
+            configured: vec![String::from("vehicles")],
+            fetch_interval: Some(10.0),
+            has_auth: None,
+            categories: vec![CategoryStatus {
+                category: String::from("vehicles"),
+                fetched,
+                changed: Some(fetched),
+                entities: Some(1),
+                size: Some(1),
+                age: 5.0,
+                stale: false,
+            }],
+        }
+    }
+
+    fn failure(time: u64) -> FailedPayload {
+        FailedPayload {
+            feed: String::from("f"),
+            category: String::from("vehicles"),
+            status: 200,
+            headers: Vec::new(),
+            error: String::from("failed to decode"),
+            time,
+            size: 3,
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn undecodable_payload_after_the_last_good_one_is_degraded() {
+        let thresholds = HealthThresholds::default();
+        let health = evaluate(&status(1000), &[failure(2000)], &thresholds);
+        assert_eq!(health.state, HealthState::Degraded);
+        assert_eq!(health.reason, "vehicles fetch failing: failed to decode");
+    }
+
+    #[test]
+    fn failure_before_the_last_good_payload_is_healthy() {
+        let thresholds = HealthThresholds::default();
+        let health = evaluate(&status(2000), &[failure(1000)], &thresholds);
+        assert_eq!(health.state, HealthState::Healthy);
+    }
+
+    #[test]
+    fn stopped_category_makes_a_feed_with_fresh_vehicles_dead() {
+        let mut status = status(2000);
+        status.categories.push(CategoryStatus {
+            category: String::from("trips"),
+            age: 600.0,
+            ..status.categories[0].clone()
+        });
+        let health = evaluate(&status, &[], &HealthThresholds::default());
+        assert_eq!(health.state, HealthState::Dead);
+        assert_eq!(health.age, Some(600.0));
+        assert_eq!(health.reason, "no new trips data for 600 seconds");
+    }
+
+    #[test]
+    fn configured_category_without_data_is_dead() {
+        let mut status = status(2000);
+        status.configured.push(String::from("alerts"));
+        let health = evaluate(&status, &[], &HealthThresholds::default());
+        assert_eq!(health.state, HealthState::Dead);
+        assert_eq!(health.age, None);
+        assert_eq!(health.reason, "no alerts data has been stored");
+    }
+
+    #[test]
+    fn worst_state_wins_over_category_order() {
+        let mut status = status(2000);
+        status.categories[0].age = 45.0;
+        status.categories.push(CategoryStatus {
+            category: String::from("alerts"),
+            age: 250.0,
+            ..status.categories[0].clone()
+        });
+        let health = evaluate(&status, &[], &HealthThresholds::default());
+        assert_eq!(health.state, HealthState::Dead);
+        assert_eq!(health.reason, "no new alerts data for 250 seconds");
+    }
+
+    #[test]
+    fn cached_check_expires() {
+        let cache = HealthCache::default();
+        assert!(cache.get(1000).is_none());
+        cache.store(
+            1000,
+            vec![evaluate(&status(1000), &[], &HealthThresholds::default())],
+        );
+        assert_eq!(
+            cache
+                .get(1000 + HEALTH_CACHE_SECONDS * 1000 - 1)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(cache.get(1000 + HEALTH_CACHE_SECONDS * 1000).is_none());
+    }
+}
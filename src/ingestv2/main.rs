@@ -8,7 +8,10 @@ extern crate color_eyre;
 use kactus::parse_protobuf_message;
 extern crate rand;
 use crate::rand::prelude::SliceRandom;
-use kactus::insert::insert_gtfs_rt_bytes;
+use kactus::insert::check_fetch_result;
+use kactus::insert::insert_feed_metadata;
+use kactus::insert::insert_counted_gtfs_rt_bytes;
+use kactus::metrics::{self, metrics};
 extern crate csv;
 use kactus::aspen;
 use std::fs::File;
@@ -43,6 +46,13 @@ async fn main() -> color_eyre::eyre::Result<()> {
         None => 50,
     };
 
+    if let Some(metrics_port) = arguments.get::<u16>("metrics_port") {
+        let metrics_address = arguments
+            .get::<String>("metrics_address")
+            .unwrap_or_else(|| String::from("127.0.0.1"));
+        metrics::serve(&metrics_address, metrics_port)?;
+    }
+
     let file = File::open(filenametouse).unwrap();
     let mut reader = csv::Reader::from_reader(BufReader::new(file));
 
@@ -84,6 +94,14 @@ async fn main() -> color_eyre::eyre::Result<()> {
         }
     }
 
+    {
+        let redisclient = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let mut con = redisclient.get_connection().unwrap();
+        for agency in agencies.iter() {
+            insert_feed_metadata(&mut con, agency);
+        }
+    }
+
     let mut lastloop;
 
 
@@ -167,17 +185,24 @@ async fn main() -> color_eyre::eyre::Result<()> {
                     )
                 );
 
-                let vehicles_result = grouped_fetch.0;
-                let trips_result = grouped_fetch.1;
-                let alerts_result = grouped_fetch.2;
+                let (vehicles_result, vehicles_entities) =
+                    check_fetch_result(&mut con, grouped_fetch.0, &agency.onetrip, "vehicles")
+                        .unzip();
+                let (trips_result, trips_entities) =
+                    check_fetch_result(&mut con, grouped_fetch.1, &agency.onetrip, "trips")
+                        .unzip();
+                let (alerts_result, alerts_entities) =
+                    check_fetch_result(&mut con, grouped_fetch.2, &agency.onetrip, "alerts")
+                        .unzip();
 
                 if vehicles_result.is_some() {
                     let bytes = vehicles_result.as_ref().unwrap().to_vec();
 
                     println!("{} vehicles bytes: {}", &agency.onetrip, bytes.len());
-                    insert_gtfs_rt_bytes(
+                    insert_counted_gtfs_rt_bytes(
                         &mut con,
                         &bytes,
+                        vehicles_entities,
                         &agency.onetrip,
                         "vehicles",
                     );
@@ -188,7 +213,13 @@ async fn main() -> color_eyre::eyre::Result<()> {
 
                     println!("{} trips bytes: {}", &agency.onetrip, bytes.len());
 
-                    insert_gtfs_rt_bytes(&mut con, &bytes, &agency.onetrip, "trips");
+                    insert_counted_gtfs_rt_bytes(
+                        &mut con,
+                        &bytes,
+                        trips_entities,
+                        &agency.onetrip,
+                        "trips",
+                    );
                 }
 
                 if alerts_result.is_some() {
@@ -196,9 +227,10 @@ async fn main() -> color_eyre::eyre::Result<()> {
 
                     println!("{} alerts bytes: {}", &agency.onetrip, bytes.len());
 
-                    insert_gtfs_rt_bytes(
+                    insert_counted_gtfs_rt_bytes(
                         &mut con,
                         &bytes,
+                        alerts_entities,
                         &agency.onetrip,
                         "alerts",
                     );
@@ -223,6 +255,11 @@ async fn main() -> color_eyre::eyre::Result<()> {
         fetches.await;
 
         let duration = lastloop.elapsed();
+        metrics().observe(
+            "kactus_ingest_loop_duration_seconds",
+            &[],
+            duration.as_secs_f64(),
+        );
 
         println!(
             "{}loop time is: {:?}{}",
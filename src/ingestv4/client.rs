@@ -32,9 +32,10 @@ async fn main() -> anyhow::Result<()> {
 
     println!("{:?}", client.removeagency(ctx, "f-bigbluebus~rt".to_string()).await?);
 
-    println!("{:#?}", &client.getagency(ctx, "f-bigbluebus~rt".to_string(), FeedType::Alerts).await.unwrap());
-
-    println!("{:#?}", parse_protobuf_message(&client.getagency(ctx, "f-bigbluebus~rt".to_string(), FeedType::Alerts).await.unwrap()));
+    match client.getagency(ctx, "f-bigbluebus~rt".to_string(), FeedType::Alerts).await? {
+        Ok(bytes) => println!("{:#?}", parse_protobuf_message(&bytes)),
+        Err(e) => println!("{} ({})", e, e.code()),
+    }
 
     Ok(())
 }
@@ -1,11 +1,11 @@
 use std::{collections::HashMap, fs::File, io::BufReader, sync::{mpsc::{self, Receiver, RecvError, Sender, TryRecvError}, Arc, Mutex}, thread::{self, sleep}, time::{Duration, Instant}};
 
-use kactus::{fetchurl, insert::insert_gtfs_rt_bytes, make_url, parse_protobuf_message, AgencyInfo, Agencyurls, IngestInfo};
+use kactus::{fetchurl, insert::{check_fetch_result, insert_counted_gtfs_rt_bytes, insert_feed_metadata}, make_url, metrics::{self, metrics}, parse_protobuf_message, AgencyInfo, Agencyurls, IngestInfo};
 use protobuf::well_known_types::duration;
 use rand::seq::SliceRandom;
 use redis::Commands;
 use reqwest::Client;
-use kactus::FeedType;
+use kactus::{error::FeedError, FeedType};
 use futures::{future, prelude::*};
 use tarpc::{
     client, context, server::{incoming::Incoming, BaseChannel}, tokio_serde::formats::Json
@@ -56,19 +56,24 @@ impl IngestInfo for KactusRPC {
             return "Error: Agency not found".to_string();
         }
     }
-    async fn getagency(self, _: context::Context, agency: String, feedtype: FeedType) -> Vec<u8> {
-        let redis_client_clone: Arc<redis::Client> = Arc::clone(&self.redis_client);
-        let mut con = redis_client_clone.get_connection().unwrap();
-        let doesexist = con.get::<String, u64>(format!("gtfsrttime|{}|{}", &agency, &feedtype));
-        if doesexist.is_err() {
-            return format!("Error in connecting to redis\n").as_bytes().to_owned();
+    async fn getagency(self, _: context::Context, agency: String, feedtype: FeedType) -> Result<Vec<u8>, FeedError> {
+        let category = feedtype.to_string();
+        let mut con = self.redis_client.get_connection().map_err(FeedError::backend)?;
+        if let Err(e) = con.get::<String, u64>(format!("gtfsrttime|{}|{}", &agency, &category)) {
+            return Err(FeedError::from_redis(&mut con, &agency, &category, e));
         }
-        let data = con.get::<String, Vec<u8>>(format!("gtfsrt|{}|{}", &agency, &feedtype));
-        if data.is_err() {
-            println!("Error: {:?}", data);
-            return format!("Error: {:?}\n", data).as_bytes().to_owned();
+        let data = match con.get::<String, Vec<u8>>(format!("gtfsrt|{}|{}", &agency, &category)) {
+            Ok(data) => data,
+            Err(e) => return Err(FeedError::from_redis(&mut con, &agency, &category, e)),
+        };
+        if let Err(e) = parse_protobuf_message(&data) {
+            return Err(FeedError::UpstreamInvalid {
+                feed: agency,
+                category,
+                reason: e.to_string(),
+            });
         }
-        return data.unwrap();
+        Ok(data)
     }
 }
 
@@ -76,6 +81,7 @@ impl IngestInfo for KactusRPC {
 async fn fetchagency(client: &Client, redis_client: &redis::Client, agency: AgencyInfo, rx: Receiver<Option<u8>>)  {
     //let client = reqwest::ClientBuilder::new().deflate(true).gzip(true).brotli(true).build().unwrap();
     let mut con = redis_client.get_connection().unwrap();
+    insert_feed_metadata(&mut con, &agency);
     loop {
         match rx.try_recv() {
             Ok(_) | Err(TryRecvError::Disconnected) => {
@@ -152,16 +158,16 @@ async fn fetchagency(client: &Client, redis_client: &redis::Client, agency: Agen
             )
         };
         
-        let vehicles_result = fetch_vehicles.await;
-        let trips_result = fetch_trips.await;
-        let alerts_result = fetch_alerts.await;
+        let (vehicles_result, vehicles_entities) = check_fetch_result(&mut con, fetch_vehicles.await, &agency.onetrip, "vehicles").unzip();
+        let (trips_result, trips_entities) = check_fetch_result(&mut con, fetch_trips.await, &agency.onetrip, "trips").unzip();
+        let (alerts_result, alerts_entities) = check_fetch_result(&mut con, fetch_alerts.await, &agency.onetrip, "alerts").unzip();
 
 
         if vehicles_result.is_some() {
             let bytes = vehicles_result.as_ref().unwrap().to_vec();
 
             println!("{} vehicles bytes: {}", &agency.onetrip, bytes.len());
-            insert_gtfs_rt_bytes(&mut con, &bytes, &agency.onetrip, "vehicles");
+            insert_counted_gtfs_rt_bytes(&mut con, &bytes, vehicles_entities, &agency.onetrip, "vehicles");
         }
 
         if trips_result.is_some() {
@@ -169,7 +175,7 @@ async fn fetchagency(client: &Client, redis_client: &redis::Client, agency: Agen
 
             println!("{} trips bytes: {}", &agency.onetrip, bytes.len());
 
-            insert_gtfs_rt_bytes(&mut con, &bytes, &agency.onetrip, "trips");
+            insert_counted_gtfs_rt_bytes(&mut con, &bytes, trips_entities, &agency.onetrip, "trips");
         }
 
         if alerts_result.is_some() {
@@ -177,8 +183,13 @@ async fn fetchagency(client: &Client, redis_client: &redis::Client, agency: Agen
 
             println!("{} alerts bytes: {}", &agency.onetrip, bytes.len());
 
-            insert_gtfs_rt_bytes(&mut con, &bytes, &agency.onetrip, "alerts");
+            insert_counted_gtfs_rt_bytes(&mut con, &bytes, alerts_entities, &agency.onetrip, "alerts");
         }
+        metrics().observe(
+            "kactus_ingest_loop_duration_seconds",
+            &[],
+            time.elapsed().as_secs_f64(),
+        );
         let duration = time.elapsed().as_secs_f32();
         if duration < agency.fetch_interval {
             let sleep_duration: f32 = agency.fetch_interval - duration;
@@ -204,6 +215,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         None => 15_000,
     };
 
+    if let Some(metrics_port) = arguments.get::<u16>("metrics_port") {
+        let metrics_address = arguments
+            .get::<String>("metrics_address")
+            .unwrap_or_else(|| String::from("127.0.0.1"));
+        metrics::serve(&metrics_address, metrics_port)?;
+    }
+
     let file = File::open(filename).unwrap();
 
     let mut reader = csv::Reader::from_reader(BufReader::new(file));
@@ -1,8 +1,32 @@
-use std::{fmt, time::Duration};
+use std::{
+    fmt,
+    hash::Hasher,
+    time::{Duration, Instant},
+};
 
 #[macro_use]
 extern crate serde_derive;
 
+pub mod access;
+pub mod cache;
+pub mod catalog;
+pub mod compression;
+pub mod diff;
+pub mod error;
+pub mod filter;
+pub mod formats;
+pub mod geo;
+pub mod health;
+pub mod merge;
+pub mod metrics;
+pub mod sse;
+pub mod tiles;
+pub mod tls;
+pub mod updates;
+pub mod vehicles;
+pub mod viewer;
+pub mod ws;
+
 
 //stores the config for each agency
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -61,7 +85,64 @@ pub trait IngestInfo {
     async fn agencies() -> String;
     async fn addagency(agency: AgencyInfo) -> String;
     async fn removeagency(agency: String) -> String;
-    async fn getagency(agency: String, feedtype: FeedType) -> Vec<u8>;
+    //the stored protobuf, or the same error the http server would answer with
+    async fn getagency(agency: String, feedtype: FeedType) -> Result<Vec<u8>, error::FeedError>;
+}
+
+//raw response from an upstream feed, kept around so failures can be inspected later
+#[derive(Debug, Clone)]
+pub struct FetchedPayload {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub bytes: Vec<u8>,
+    //from sending the request to having the whole body
+    pub elapsed: Duration,
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    NoUrl,
+    Status(FetchedPayload),
+    Network(String),
+}
+
+//the last payload for a feed/category that was rejected or could not be decoded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailedPayload {
+    pub feed: String,
+    pub category: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub error: String,
+    pub time: u64,
+    pub size: usize,
+    pub truncated: bool,
+}
+
+//published on gtfsrtupdate|feed|category every time a snapshot is inserted
+//subscribe to gtfsrtupdate|* to hear about all of them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeedUpdateNotification {
+    pub feed: String,
+    pub category: String,
+    //the new gtfsrttime, in milliseconds
+    pub time: u64,
+    //same as the hash header on /gtfsrt
+    pub hash: u64,
+    pub size: usize,
+}
+
+pub const FEED_UPDATE_PATTERN: &str = "gtfsrtupdate|*";
+
+pub fn feed_update_channel(feed: &str, category: &str) -> String {
+    format!("gtfsrtupdate|{}|{}", feed, category)
+}
+
+//hash of a stored payload, used for the hash header and bodyhash
+pub fn feed_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = metrohash::MetroHash64::new();
+    hasher.write(bytes);
+    hasher.finish()
 }
 
 pub async fn fetchurl(
@@ -71,10 +152,11 @@ pub async fn fetchurl(
     auth_password: &String,
     client: &reqwest::Client,
     timeoutforfetch: u64,
-) -> Option<Vec<u8>> {
+) -> Result<FetchedPayload, FetchError> {
     if url.is_none() || url.to_owned().unwrap().contains("kactus") {
-        return None;
+        return Err(FetchError::NoUrl);
     }
+    let started = Instant::now();
     let mut req = client.get(url.to_owned().unwrap());
 
     if auth_type == "header" {
@@ -88,22 +170,40 @@ pub async fn fetchurl(
 
     match resp {
         Ok(resp) => {
-            if resp.status().is_success() {
-                match resp.bytes().await {
-                    Ok(bytes_pre) => {
-                        let bytes = bytes_pre.to_vec();
-                        Some(bytes)
-                    }
-                    _ => None,
+            let status = resp.status();
+            let headers = resp
+                .headers()
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        name.as_str().to_string(),
+                        String::from_utf8_lossy(value.as_bytes()).to_string(),
+                    )
+                })
+                .collect::<Vec<(String, String)>>();
+            let bytes = match resp.bytes().await {
+                Ok(bytes_pre) => bytes_pre.to_vec(),
+                Err(e) => {
+                    println!("error reading body: {:?}", e);
+                    return Err(FetchError::Network(e.to_string()));
                 }
+            };
+            let payload = FetchedPayload {
+                status: status.as_u16(),
+                headers,
+                bytes,
+                elapsed: started.elapsed(),
+            };
+            if status.is_success() {
+                Ok(payload)
             } else {
-                println!("{}:{:?}", &url.clone().unwrap(), resp.status());
-                None
+                println!("{}:{:?}", &url.clone().unwrap(), status);
+                Err(FetchError::Status(payload))
             }
         }
         Err(e) => {
             println!("error fetching url: {:?}", e);
-            None
+            Err(FetchError::Network(e.to_string()))
         }
     }
 }
@@ -140,21 +240,138 @@ pub fn parse_protobuf_message(
 
 pub mod insert {
 
+    use crate::{
+        catalog, feed_hash, metrics::metrics, feed_update_channel, parse_protobuf_message, AgencyInfo, FailedPayload,
+        FeedUpdateNotification, FetchError, FetchedPayload,
+    };
     use prost::Message;
-    use redis::{Commands, Connection};
+    use redis::{Commands, Connection, RedisResult};
     use std::{fs::File, io::{self, Write}, time::{SystemTime, UNIX_EPOCH}};
 
+    //failed payloads larger than this are cut off before being stored
+    pub const MAX_FAILED_PAYLOAD_BYTES: usize = 1024 * 1024;
+
+    //decides what to do with the result of fetchurl
+    //good payloads are handed back for inserting with their entity count, rejected or undecodable ones are kept for debugging
+    pub fn check_fetch_result(
+        con: &mut Connection,
+        result: Result<FetchedPayload, FetchError>,
+        onetrip: &str,
+        category: &str,
+    ) -> Option<(Vec<u8>, usize)> {
+        let labels = [("feed", onetrip), ("category", category)];
+        if let Ok(payload) | Err(FetchError::Status(payload)) = &result {
+            metrics().observe(
+                "kactus_fetch_duration_seconds",
+                &labels,
+                payload.elapsed.as_secs_f64(),
+            );
+            metrics().inc("kactus_fetch_bytes_total", &labels, payload.bytes.len() as f64);
+        }
+        let outcome = |outcome: &str| {
+            metrics().inc(
+                "kactus_fetches_total",
+                &[("feed", onetrip), ("category", category), ("outcome", outcome)],
+                1.0,
+            )
+        };
+
+        match result {
+            //an undecodable payload isn't inserted, so the last good snapshot keeps being served
+            Ok(payload) => match parse_protobuf_message(&payload.bytes) {
+                Ok(message) => {
+                    outcome("ok");
+                    Some((payload.bytes, message.entity.len()))
+                }
+                Err(e) => {
+                    println!("{} {} failed to decode: {}", onetrip, category, e);
+                    insert_failed_payload(con, &payload, &e.to_string(), onetrip, category);
+                    outcome("undecodable");
+                    None
+                }
+            },
+            Err(FetchError::Status(payload)) => {
+                let error = format!("upstream returned status {}", payload.status);
+                insert_failed_payload(con, &payload, &error, onetrip, category);
+                outcome("status");
+                None
+            }
+            Err(FetchError::Network(_)) => {
+                outcome("network");
+                None
+            }
+            Err(FetchError::NoUrl) => None,
+        }
+    }
+
+    pub fn insert_failed_payload(
+        con: &mut Connection,
+        payload: &FetchedPayload,
+        error: &str,
+        onetrip: &str,
+        category: &str,
+    ) {
+        let now_millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let truncated = payload.bytes.len() > MAX_FAILED_PAYLOAD_BYTES;
+        let stored = &payload.bytes[..payload.bytes.len().min(MAX_FAILED_PAYLOAD_BYTES)];
+
+        let failed = FailedPayload {
+            feed: onetrip.to_string(),
+            category: category.to_string(),
+            status: payload.status,
+            headers: payload.headers.clone(),
+            error: error.to_string(),
+            time: now_millis,
+            size: payload.bytes.len(),
+            truncated,
+        };
+
+        //only kept for debugging, so redis failing here mustn't stop the ingest loop
+        let stored: RedisResult<()> = redis::pipe()
+            .set(
+                format!("gtfsrtfailed|{}|{}", &onetrip, &category),
+                serde_json::to_string(&failed).unwrap(),
+            )
+            .ignore()
+            .set(
+                format!("gtfsrtfailedbytes|{}|{}", &onetrip, &category),
+                stored,
+            )
+            .ignore()
+            .query(con);
+        if let Err(e) = stored {
+            println!("Could not store failed payload for {} {}: {}", onetrip, category, e);
+        }
+    }
+
     pub fn insert_gtfs_rt_bytes(
         con: &mut Connection,
         bytes: &Vec<u8>,
         onetrip: &str,
         category: &str,
+    ) {
+        let entities = parse_protobuf_message(bytes)
+            .ok()
+            .map(|message| message.entity.len());
+        insert_counted_gtfs_rt_bytes(con, bytes, entities, onetrip, category);
+    }
+
+    //for bytes that were already decoded, like the ones check_fetch_result hands back
+    pub fn insert_counted_gtfs_rt_bytes(
+        con: &mut Connection,
+        bytes: &Vec<u8>,
+        entities: Option<usize>,
+        onetrip: &str,
+        category: &str,
     ) {
         let now_millis = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
-            .as_millis()
-            .to_string();
+            .as_millis() as u64;
 
         let key: String = format!("gtfsrt|{}|{}", &onetrip, &category);
         let _: () = con.set(key.clone(), bytes).unwrap();
@@ -165,7 +382,8 @@ pub mod insert {
             "*",
             &[(key.clone(), msg.clone())],
         );*/
-        inserttimes(con, &onetrip, &category, &now_millis);
+        inserttimes(con, &onetrip, &category, &now_millis.to_string());
+        publish_update(con, &bytes, entities, &onetrip, &category, now_millis);
         //let _ = con.set_read_timeout(Some(Duration::new(10, 0)));
     }
     pub fn persist_gtfs_rt_bytes(
@@ -205,8 +423,7 @@ pub mod insert {
         let now_millis = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
-            .as_millis()
-            .to_string();
+            .as_millis() as u64;
 
         let bytes: Vec<u8> = data.encode_to_vec();
 
@@ -214,7 +431,62 @@ pub mod insert {
             .set(format!("gtfsrt|{}|{}", &onetrip, &category), bytes.to_vec())
             .unwrap();
 
-        inserttimes(con, &onetrip, &category, &now_millis);
+        inserttimes(con, &onetrip, &category, &now_millis.to_string());
+        publish_update(con, &bytes, Some(data.entity.len()), &onetrip, &category, now_millis);
+    }
+
+    //static details of a feed for the catalog, written when the ingest engine loads its config
+    pub fn insert_feed_metadata(con: &mut Connection, agency: &AgencyInfo) {
+        insert_fetch_interval(con, &agency.onetrip, agency.fetch_interval);
+        catalog::record_metadata(con, agency).unwrap();
+    }
+
+    //lets the server work out how long clients may cache a feed for
+    pub fn insert_fetch_interval(con: &mut Connection, onetrip: &str, fetch_interval: f32) {
+        let _: () = con
+            .set(format!("gtfsrtinterval|{}", &onetrip), fetch_interval)
+            .unwrap();
+    }
+
+    //updates the catalog and tells subscribers a new snapshot is in
+    //sent after the keys are written so they can read it straight away
+    fn publish_update(
+        con: &mut Connection,
+        bytes: &[u8],
+        entities: Option<usize>,
+        onetrip: &str,
+        category: &str,
+        now_millis: u64,
+    ) {
+        let notification = FeedUpdateNotification {
+            feed: onetrip.to_string(),
+            category: category.to_string(),
+            time: now_millis,
+            hash: feed_hash(bytes),
+            size: bytes.len(),
+        };
+        catalog::record_snapshot(
+            con,
+            onetrip,
+            category,
+            notification.time,
+            notification.hash,
+            notification.size,
+            entities,
+        )
+        .unwrap();
+        metrics().touch(
+            "kactus_feed_age_seconds",
+            &[("feed", onetrip), ("category", category)],
+        );
+        //the snapshot is already stored, servers that miss this pick it up on the next request
+        let published: RedisResult<()> = con.publish(
+            feed_update_channel(onetrip, category),
+            serde_json::to_string(&notification).unwrap(),
+        );
+        if let Err(e) = published {
+            println!("Could not publish update for {} {}: {}", onetrip, category, e);
+        }
     }
 
     fn inserttimes(con: &mut Connection, onetrip: &str, category: &str, now_millis: &String) {
@@ -1,55 +1,58 @@
-use actix::{Actor, StreamHandler};
 use actix_web::{
-    middleware, middleware::DefaultHeaders, web, App, HttpRequest, HttpResponse, HttpServer,
-    Responder,
+    body::MessageBody,
+    dev::Service,
+    error::{InternalError, JsonPayloadError, PathError, QueryPayloadError},
+    http::{header, header::HttpDate, StatusCode}, middleware, middleware::DefaultHeaders, web, App, HttpMessage, HttpRequest, HttpResponse,
+    HttpResponseBuilder, HttpServer, Responder,
 };
+use futures::future::{ready, Either};
 use actix_web_actors::ws;
-use gtfs_rt::{EntitySelector, FeedEntity, FeedHeader, FeedMessage};
+use prost::Message;
 use rand::Rng;
 use redis::Commands;
 extern crate qstring;
 
-use kactus::parse_protobuf_message;
+use kactus::{
+    access::{
+        constant_time_eq, flush_usage, forwarded_hops, load_api_keys, load_restricted_feeds, usage,
+        AccessControl, ApiKey, Denied,
+    },
+    cache::{Base, Snapshot, SnapshotCache},
+    catalog::{self, CatalogQuery},
+    compression::{choose_encoding, compress, Encoding},
+    diff::diff,
+    error::{error_body, FeedError},
+    filter::EntityFilter,
+    formats::{trip_updates_csv, vehicles_csv, FeedDescriptor, OutputFormat},
+    geo::{parse_number, vehicles_geojson},
+    health::{check_all, FeedHealth, HealthCache, HealthState, HealthThresholds},
+    merge::{merge_categories, merge_feeds},
+    metrics::metrics,
+    sse::{event_stream, EventData},
+    tiles::{vehicle_tile, TileCache, TileId},
+    tls,
+    updates::{watch, FeedUpdates},
+    vehicles::{in_envelope, nearby, with_label, AllVehicles, VehicleMatch},
+    viewer::ViewerPage,
+    ws::FeedSocket,
+    FailedPayload,
+};
 use qstring::QString;
 use serde::Serialize;
-use std::{hash::Hasher, time::{Instant, SystemTime, UNIX_EPOCH}};
-
-pub struct GtfsWs {
-    feed: String,
-    category: String,
-    suicidebutton: bool,
-    //skipfailure: Option<String>,
-}
+use gtfs_rt::FeedMessage;
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hasher,
+    net::IpAddr,
+    os::unix::fs::FileTypeExt,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-impl Actor for GtfsWs {
-    type Context = ws::WebsocketContext<Self>;
-    fn started(&mut self, ctx: &mut Self::Context) {
-        let redisclient = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
-        let mut con = redisclient.get_connection().unwrap();
-        let feed = &self.feed;
-        let category = &self.category;
-        let doesexist = con.get::<String, u64>(format!("gtfsrttime|{}|{}", &feed, &category));
-        if doesexist.is_err() {
-            ctx.text(format!("Error in connecting to redis\n"));
-            return ctx.close(None);
-        }
-        let data = con.get::<String, Vec<u8>>(format!("gtfsrt|{}|{}", &feed, &category));
-        if data.is_err() {
-            println!("Error: {:?}", data);
-            ctx.text(format!("Error: {:?}\n", data));
-            return ctx.close(None);
-        }
-        let data = data.unwrap();
-        if self.suicidebutton {
-            return ctx.binary(data);
-        }
-        ctx.binary(data)
-    }
-}
-impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for GtfsWs {
-    fn handle(&mut self, _msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        let _ = ctx;
-    }
+#[derive(Clone)]
+pub struct ServerConfig {
+    //key required for the /admin endpoints, they are disabled when this is None
+    admin_key: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -70,57 +73,248 @@ async fn index(_req: HttpRequest) -> impl Responder {
         .body("Hello world!")
 }
 
-async fn gtfsrt(req: HttpRequest) -> impl Responder {
-    let redisclient = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
-    let mut con = redisclient.get_connection().unwrap();
+//the feed a request was answered from, "merged" for several, used as the metrics label
+struct ServedFeed(String);
+
+//only called once the feed turned out to have a snapshot, so made up feed names can't grow the metrics
+fn served(req: &HttpRequest, feed: &str) {
+    let feed = match feed.contains(',') {
+        true => "merged",
+        false => feed,
+    };
+    req.extensions_mut().insert(ServedFeed(feed.to_string()));
+}
+
+//the key the access middleware accepted for this request, if any
+fn request_api_key(req: &HttpRequest) -> Option<ApiKey> {
+    req.extensions().get::<ApiKey>().cloned()
+}
+
+//answers requests naming a restricted feed the consumer's key doesn't list, merged requests included
+fn restricted_response(req: &HttpRequest, access: &AccessControl, feeds: &str) -> Option<HttpResponse> {
+    let api_key = request_api_key(req);
+    if feeds
+        .split(',')
+        .all(|feed| access.can_see(feed.trim(), api_key.as_ref()))
+    {
+        return None;
+    }
+    Some(request_error(
+        StatusCode::FORBIDDEN,
+        "restricted_feed",
+        "feed requires an authorised API key",
+    ))
+}
+
+//every error is answered with the same json shape, see kactus::error
+fn error_response(status: StatusCode, body: serde_json::Value) -> HttpResponse {
+    HttpResponse::build(status)
+        .insert_header(("Content-Type", "application/json"))
+        .body(format!("{}\n", body))
+}
+
+fn request_error(status: StatusCode, code: &str, message: &str) -> HttpResponse {
+    error_response(status, error_body(code, message))
+}
+
+fn bad_request(message: impl std::fmt::Display) -> HttpResponse {
+    request_error(
+        StatusCode::BAD_REQUEST,
+        "invalid_request",
+        &message.to_string(),
+    )
+}
+
+//extractors that fail answer with the same json errors as the handlers instead of plain text
+fn path_error(error: PathError, _req: &HttpRequest) -> actix_web::Error {
+    let response = request_error(StatusCode::NOT_FOUND, "not_found", &error.to_string());
+    InternalError::from_response(error, response).into()
+}
+
+fn query_error(error: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = bad_request(&error);
+    InternalError::from_response(error, response).into()
+}
+
+fn json_error(error: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = bad_request(&error);
+    InternalError::from_response(error, response).into()
+}
+
+async fn not_found() -> HttpResponse {
+    request_error(StatusCode::NOT_FOUND, "not_found", "No such endpoint")
+}
+
+fn feed_error_response(error: &FeedError) -> HttpResponse {
+    let status = StatusCode::from_u16(error.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    error_response(status, error.body())
+}
+
+//a redis outage is answered with backend_unavailable instead of taking the worker down
+fn redis_connection() -> Result<redis::Connection, FeedError> {
+    redis::Client::open("redis://127.0.0.1:6379/")
+        .and_then(|redisclient| redisclient.get_connection())
+        .map_err(FeedError::backend)
+}
+
+//the encoding the client prefers, or the not_acceptable error when it ruled out everything
+fn response_encoding(req: &HttpRequest) -> Result<Encoding, HttpResponse> {
+    match req.headers().get("Accept-Encoding") {
+        Some(accept_encoding) => choose_encoding(accept_encoding.to_str().unwrap_or(""))
+            .ok_or_else(|| {
+                request_error(
+                    StatusCode::NOT_ACCEPTABLE,
+                    "not_acceptable",
+                    "none of the encodings in Accept-Encoding are available",
+                )
+            }),
+        None => Ok(Encoding::Identity),
+    }
+}
+
+//compresses a response like middleware::Compress would, for /gtfsrt which isn't wrapped in it
+//so the precompressed snapshots get to answer every Accept-Encoding themselves
+async fn compressed(req: &HttpRequest, response: HttpResponse) -> HttpResponse {
+    if !response.status().is_success() || response.headers().contains_key("Content-Encoding") {
+        return response;
+    }
+    let encoding = match response_encoding(req) {
+        Ok(Encoding::Identity) => return response,
+        Ok(encoding) => encoding,
+        Err(response) => return response,
+    };
+    let (response, body) = response.into_parts();
+    let body = match body.try_into_bytes() {
+        Ok(body) => body,
+        Err(body) => return response.set_body(body),
+    };
+    match web::block(move || compress(&body, encoding)).await {
+        Ok(body) => {
+            let mut response = response.set_body(body);
+            let headers = response.headers_mut();
+            headers.insert(
+                header::CONTENT_ENCODING,
+                header::HeaderValue::from_static(encoding.header_value()),
+            );
+            headers.insert(header::VARY, header::HeaderValue::from_static("Accept-Encoding"));
+            response.map_into_boxed_body()
+        }
+        Err(e) => request_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            &e.to_string(),
+        ),
+    }
+}
+
+//sends a snapshot in whichever precompressed encoding the client prefers
+async fn protobuf_response(
+    req: &HttpRequest,
+    mut response: HttpResponseBuilder,
+    snapshot: &Arc<Snapshot>,
+) -> HttpResponse {
+    let encoding = match response_encoding(req) {
+        Ok(encoding) => encoding,
+        Err(response) => return response,
+    };
+    let body = match snapshot.encoded(encoding) {
+        Some(body) => body,
+        //the first client asking for an encoding compresses the snapshot on the blocking pool
+        None => {
+            let snapshot = snapshot.clone();
+            match web::block(move || snapshot.encode(encoding)).await {
+                Ok(body) => body,
+                Err(e) => {
+                    return request_error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "internal",
+                        &e.to_string(),
+                    )
+                }
+            }
+        }
+    };
+    response
+        .insert_header(("Content-Type", "application/x-google-protobuf"))
+        .insert_header(("Content-Encoding", encoding.header_value()))
+        .insert_header(("Vary", "Accept-Encoding"))
+        .body(body)
+}
 
+async fn gtfsrt(
+    req: HttpRequest,
+    cache: web::Data<SnapshotCache>,
+    descriptor: web::Data<FeedDescriptor>,
+    access: web::Data<AccessControl>,
+) -> impl Responder {
     let qs = QString::from(req.query_string());
     let feed = match qs.get("feed") {
         Some(feed) => feed,
-        None => {
-            return HttpResponse::NotFound()
-                .insert_header(("Content-Type", "text/plain"))
-                .body("Error: No feed specified\n")
-        }
+        None => return bad_request("No feed specified"),
     };
     let category = match qs.get("category") {
         Some(category) => category,
-        None => {
-            return HttpResponse::NotFound()
-                .insert_header(("Content-Type", "text/plain"))
-                .body("Error: No category specified\n")
+        None => return bad_request("No category specified"),
+    };
+    if let Some(response) = restricted_response(&req, &access, feed) {
+        return response;
+    }
+    //other formats are rendered like /gtfsrtasjson, without the protobuf only caching and diffs
+    match parse_format(&qs, category, OutputFormat::Protobuf) {
+        Ok(OutputFormat::Protobuf) => {}
+        Ok(_) if qs.get("since").is_some() => {
+            return bad_request("since only works with protobuf output")
         }
+        Ok(format) => {
+            let response = formatted_feed(&req, &qs, feed, category, format, &cache, &descriptor);
+            return compressed(&req, response).await;
+        }
+        Err(e) => return bad_request(e),
+    }
+    let max_age = match parse_max_age(&qs) {
+        Ok(max_age) => max_age,
+        Err(e) => return bad_request(e),
+    };
+    let mut con = match redis_connection() {
+        Ok(con) => con,
+        Err(e) => return feed_error_response(&e),
     };
-    let doesexist = con.get::<String, u64>(format!("gtfsrttime|{}|{}", feed, category));
-    if doesexist.is_err() {
-        return HttpResponse::InternalServerError()
-            .insert_header(("Content-Type", "text/plain"))
-            .body(format!("Error in connecting to redis\n"));
+    if feed.contains(',') || category == "all" {
+        return gtfsrt_merged(&req, feed, category, max_age, &cache, &access, &mut con);
     }
-    let data = con.get::<String, Vec<u8>>(format!("gtfsrt|{}|{}", &feed, &category));
-    if data.is_err() {
-        return HttpResponse::InternalServerError()
-            .insert_header(("Content-Type", "text/plain"))
-            .body(format!("Error: {:?}\n", data.unwrap()));
+    let snapshot = match cache.snapshot(&mut con, feed, category) {
+        Ok(snapshot) => snapshot,
+        Err(e) => return feed_error_response(&e),
+    };
+    served(&req, feed);
+    if let Err(e) = snapshot.check_age(feed, category, max_age, now_millis()) {
+        return feed_error_response(&e);
     }
-    let data = data.unwrap();
+    let doesexist = snapshot.time;
     let suicidebutton = qs.get("suicidebutton");
     if suicidebutton.is_some() {
         let suicidebutton = suicidebutton.unwrap();
         if suicidebutton == "true" {
-            return HttpResponse::Ok()
-                .insert_header(("Content-Type", "application/x-google-protobuf"))
-                .body(data);
+            return protobuf_response(&req, HttpResponse::Ok(), &snapshot).await;
         }
     }
+    //the same upstream_invalid error /gtfsrtasjson gives
+    //skipfailure=true still gets the stored bytes, for clients that decode what they can
+    if let Err(e) = snapshot.message(feed, category) {
+        if qs.get("skipfailure") != Some("true") {
+            return feed_error_response(&e);
+        }
+    }
+    let etag = etag(&snapshot, &qs);
+    if not_modified(&req, &snapshot, &etag) {
+        let mut response = HttpResponse::NotModified();
+        cache_headers(&mut response, &snapshot, &etag, access.is_private(feed));
+        return response.finish();
+    }
     let timeofclientcache = qs.get("timeofcache");
-    let proto = parse_protobuf_message(&data);
+    let proto = &snapshot.proto;
     let hashofresult = match proto {
-        Ok(_) => {
-            let mut hasher = metrohash::MetroHash64::new();
-            hasher.write(data.as_slice());
-            hasher.finish()
-        },
+        Ok(_) => snapshot.hash,
         Err(_) => {
             let mut rng = rand::thread_rng();
             rng.gen::<u64>()
@@ -130,39 +324,27 @@ async fn gtfsrt(req: HttpRequest) -> impl Responder {
         let timeofclientcache = timeofclientcache.unwrap();
         let timeofclientcache = (*timeofclientcache).parse::<u64>();
         if timeofclientcache.is_ok() {
-            let timeofclientcache = timeofclientcache.unwrap();
-            if timeofclientcache >= doesexist.unwrap() {
+            let timeofclientcache = as_millis(timeofclientcache.unwrap());
+            if timeofclientcache >= doesexist {
                 return HttpResponse::NoContent().body("");
             }
-            match &proto {
+            match proto {
                 Ok(proto) => {
                     let headertimestamp = proto.header.timestamp;
                     if headertimestamp.is_some() {
-                        if timeofclientcache >= headertimestamp.unwrap() {
+                        if timeofclientcache >= as_millis(headertimestamp.unwrap()) {
                             return HttpResponse::NoContent().body("");
                         }
                     }
                 }
-                Err(bruh) => {
-                    println!("{:#?}", bruh);
-                    let skipfailure = qs.get("skipfailure");
-                    let mut allowcrash = true;
-                    if skipfailure.is_some() {
-                        if skipfailure.unwrap() == "true" {
-                            allowcrash = false;
-                        }
-                    }
-                    if allowcrash {
-                        return HttpResponse::InternalServerError()
-                            .body("protobuf failed to parse");
-                    }
-                }
+                //only reached with skipfailure=true, anything else was turned away above
+                Err(bruh) => println!("{:#?}", bruh),
             }
         }
         let hashofbodyclient = qs.get("bodyhash");
         if hashofbodyclient.is_some() {
             let hashofbodyclient = hashofbodyclient.unwrap();
-            if (&proto).is_ok() {
+            if proto.is_ok() {
                 let clienthash = hashofbodyclient.parse::<u64>();
                 if clienthash.is_ok() {
                     let clienthash = clienthash.unwrap();
@@ -173,74 +355,530 @@ async fn gtfsrt(req: HttpRequest) -> impl Responder {
             }
         }
     }
-    HttpResponse::Ok()
-        .insert_header(("Content-Type", "application/x-google-protobuf"))
-        .insert_header(("hash", hashofresult))
-        .body(data)
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("hash", hashofresult));
+    cache_headers(&mut response, &snapshot, &etag, access.is_private(feed));
+    let filter = match EntityFilter::from_query(&qs, &category) {
+        Ok(filter) => filter,
+        Err(e) => return bad_request(e),
+    };
+    if let Some(since) = qs.get("since") {
+        let since = match since.parse::<u64>() {
+            Ok(since) => as_millis(since),
+            Err(_) => {
+                return bad_request("since must be the X-Feed-Fetched time of an earlier response")
+            }
+        };
+        //a client that's already up to date gets a differential with no entities
+        //a base that's no longer kept gets the full dataset, which clients apply as a reset
+        let base = match since == snapshot.time {
+            true => Some(Base::of(&snapshot)),
+            false => cache.previous(feed, category, since),
+        };
+        if let Some(base) = base {
+            response.insert_header(("X-Feed-Incrementality", "differential"));
+            if filter.is_empty() {
+                return match snapshot.diff_from(&base) {
+                    Some(encoded) => response
+                        .insert_header(("Content-Type", "application/x-google-protobuf"))
+                        .body(encoded),
+                    None => invalid_snapshot_response(feed, category, &[&snapshot]),
+                };
+            }
+            let filtered_base = match base.time == snapshot.time {
+                true => snapshot.filtered(&filter),
+                false => base.message().map(|base| filter.apply(&base)),
+            };
+            return match (filtered_base, snapshot.filtered(&filter)) {
+                (Some(base), Some(current)) => response
+                    .insert_header(("Content-Type", "application/x-google-protobuf"))
+                    .body(diff(&base, &current).encode_to_vec()),
+                _ => invalid_snapshot_response(feed, category, &[&snapshot]),
+            };
+        }
+        response.insert_header(("X-Feed-Incrementality", "full_dataset"));
+    }
+    if !filter.is_empty() {
+        return match snapshot.filtered(&filter) {
+            Some(filtered) => response
+                .insert_header(("Content-Type", "application/x-google-protobuf"))
+                .body(filtered.encode_to_vec()),
+            None => invalid_snapshot_response(feed, category, &[&snapshot]),
+        };
+    }
+    protobuf_response(&req, response, &snapshot).await
 }
 
-//NOT PROTOBUF this is the list of available agencies
-async fn gtfsrttimes(_req: HttpRequest) -> impl Responder {
-    let redisclient = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
-    let mut con = redisclient.get_connection().unwrap();
+//max_age= turns snapshots fetched longer ago than that many seconds into a stale error
+fn parse_max_age(qs: &QString) -> Result<Option<f64>, String> {
+    match qs.get("max_age") {
+        Some(max_age) => match max_age.parse::<f64>() {
+            Ok(max_age) if max_age >= 0.0 => Ok(Some(max_age)),
+            _ => Err(String::from("max_age must be a number of seconds")),
+        },
+        None => Ok(None),
+    }
+}
 
-    let mut vecoftimes: Vec<FeedTimes> = Vec::new();
+//answers with the first of the snapshots that didn't decode
+fn invalid_snapshot_response(feed: &str, category: &str, snapshots: &[&Snapshot]) -> HttpResponse {
+    match snapshots
+        .iter()
+        .find_map(|snapshot| snapshot.message(feed, category).err())
+    {
+        Some(e) => feed_error_response(&e),
+        None => request_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            "snapshot could not be filtered",
+        ),
+    }
+}
 
-    let startiterator = Instant::now();
+const ALL_CATEGORIES: [&str; 3] = ["vehicles", "trips", "alerts"];
 
-    let keys = con.keys::<String, Vec<String>>(String::from("gtfsrtexists|*"));
+//snapshots behind a request for several feeds (feed=a,b,c) or several categories (category=all)
+struct MergedSnapshots {
+    feeds: Vec<(String, Vec<(String, Arc<Snapshot>)>)>,
+}
 
-    match keys {
-        Ok(data) => {
-            let mut keys: Vec<String> = data;
+impl MergedSnapshots {
+    fn load(
+        cache: &SnapshotCache,
+        con: &mut redis::Connection,
+        feeds: &str,
+        category: &str,
+        max_age: Option<f64>,
+    ) -> Result<MergedSnapshots, FeedError> {
+        let mut unique_feeds: Vec<&str> = Vec::new();
+        for feed in feeds.split(',').map(|feed| feed.trim()) {
+            if !feed.is_empty() && !unique_feeds.contains(&feed) {
+                unique_feeds.push(feed);
+            }
+        }
 
-            keys.sort();
+        let now = now_millis();
+        let mut loaded = Vec::new();
+        for feed in unique_feeds {
+            let mut snapshots = Vec::new();
+            if category == "all" {
+                //feeds don't have to provide every category, but an unknown feed or redis failing still fails
+                for category in ALL_CATEGORIES {
+                    match cache.snapshot(con, feed, category) {
+                        Ok(snapshot) => snapshots.push((category.to_string(), snapshot)),
+                        Err(FeedError::UnknownCategory { .. }) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                if snapshots.is_empty() {
+                    return Err(FeedError::UnknownCategory {
+                        feed: feed.to_string(),
+                        category: category.to_string(),
+                    });
+                }
+            } else {
+                snapshots.push((category.to_string(), cache.snapshot(con, feed, category)?));
+            }
+            for (category, snapshot) in snapshots.iter() {
+                snapshot.check_age(feed, category, max_age, now)?;
+            }
+            loaded.push((feed.to_string(), snapshots));
+        }
+        Ok(MergedSnapshots { feeds: loaded })
+    }
 
-            for key in keys.iter_mut() {
-                let feed = key.replace("gtfsrtexists|", "");
+    //changes whenever any of the snapshots or the filters do
+    fn etag(&self, qs: &QString) -> String {
+        let mut hasher = metrohash::MetroHash64::new();
+        hasher.write_u64(variant(qs));
+        for (feed, snapshots) in self.feeds.iter() {
+            hasher.write(feed.as_bytes());
+            for (category, snapshot) in snapshots.iter() {
+                hasher.write(category.as_bytes());
+                hasher.write_u64(snapshot.hash);
+            }
+        }
+        format!("W/\"{:x}\"", hasher.finish())
+    }
 
-                // Print the first field of the record
+    //last modified by the newest snapshot, good until the first one is due again,
+    //and private when any of the feeds is
+    fn cache_headers(&self, response: &mut HttpResponseBuilder, access: &AccessControl) {
+        let now = now_millis();
+        let snapshots: Vec<&Snapshot> = self
+            .feeds
+            .iter()
+            .flat_map(|(_, snapshots)| snapshots.iter().map(|(_, snapshot)| snapshot.as_ref()))
+            .collect();
+        if let Some(newest) = snapshots.iter().map(|snapshot| snapshot.time).max() {
+            response.insert_header((
+                "Last-Modified",
+                HttpDate::from(UNIX_EPOCH + Duration::from_millis(newest)),
+            ));
+        }
+        let fresh_for = snapshots
+            .iter()
+            .map(|snapshot| fresh_for(snapshot, now))
+            .collect::<Option<Vec<u64>>>()
+            .and_then(|fresh_for| fresh_for.into_iter().min());
+        let private = self.feeds.iter().any(|(feed, _)| access.is_private(feed));
+        response.insert_header(("Cache-Control", cache_control(fresh_for, private)));
+    }
 
-                let vehicles = con.get::<String, u64>(format!("gtfsrttime|{}|vehicles", feed));
-                let trips = con.get::<String, u64>(format!("gtfsrttime|{}|trips", feed));
-                let alerts = con.get::<String, u64>(format!("gtfsrttime|{}|alerts", feed));
+    fn message(&self) -> Result<FeedMessage, FeedError> {
+        let mut messages = Vec::new();
+        for (feed, snapshots) in self.feeds.iter() {
+            let mut protos = Vec::new();
+            for (category, snapshot) in snapshots.iter() {
+                protos.push((category.as_str(), snapshot.message(feed, category)?));
+            }
+            let message = if protos.len() == 1 {
+                protos[0].1.clone()
+            } else {
+                merge_categories(&protos)
+            };
+            messages.push((feed.as_str(), message));
+        }
+        if messages.len() == 1 {
+            return Ok(messages.pop().unwrap().1);
+        }
+        let messages: Vec<(&str, &FeedMessage)> = messages
+            .iter()
+            .map(|(feed, message)| (*feed, message))
+            .collect();
+        Ok(merge_feeds(&messages))
+    }
+}
 
-                let vehicles = match vehicles {
-                    Ok(data) => Some(data),
-                    Err(_e) => None,
-                };
+//several feeds or categories merged into one message
+fn gtfsrt_merged(
+    req: &HttpRequest,
+    feeds: &str,
+    category: &str,
+    max_age: Option<f64>,
+    cache: &SnapshotCache,
+    access: &AccessControl,
+    con: &mut redis::Connection,
+) -> HttpResponse {
+    let qs = QString::from(req.query_string());
+    //diffs are made against one stored snapshot, a merged message has none
+    if qs.get("since").is_some() {
+        return bad_request("since doesn't work with several feeds or category=all");
+    }
+    let snapshots = match MergedSnapshots::load(cache, con, feeds, category, max_age) {
+        Ok(snapshots) => snapshots,
+        Err(e) => return feed_error_response(&e),
+    };
+    served(req, feeds);
+    let etag = snapshots.etag(&qs);
 
-                let trips = match trips {
-                    Ok(data) => Some(data),
-                    Err(_e) => None,
-                };
+    if let Some(if_none_match) = req.headers().get("If-None-Match") {
+        if etag_matches(if_none_match, &etag) {
+            let mut response = HttpResponse::NotModified();
+            snapshots.cache_headers(&mut response, access);
+            return response.insert_header(("ETag", etag)).finish();
+        }
+    }
 
-                let alerts = match alerts {
-                    Ok(data) => Some(data),
-                    Err(_e) => None,
-                };
+    let merged = match snapshots.message() {
+        Ok(merged) => merged,
+        Err(e) => return feed_error_response(&e),
+    };
 
-                let feedtime = FeedTimes {
-                    feed: feed.clone(),
-                    vehicles: vehicles,
-                    trips: trips,
-                    alerts: alerts,
-                };
+    let filter = match EntityFilter::from_query(&qs, category) {
+        Ok(filter) => filter,
+        Err(e) => return bad_request(e),
+    };
+    let merged = if filter.is_empty() {
+        merged
+    } else {
+        filter.apply(&merged)
+    };
+
+    let mut response = HttpResponse::Ok();
+    snapshots.cache_headers(&mut response, access);
+    response
+        .insert_header(("Content-Type", "application/x-google-protobuf"))
+        .insert_header(("ETag", etag))
+        .body(merged.encode_to_vec())
+}
+
+#[derive(Serialize)]
+struct HealthSummary {
+    healthy: usize,
+    degraded: usize,
+    dead: usize,
+    feeds: Vec<FeedHealth>,
+}
+
+async fn health(
+    req: HttpRequest,
+    thresholds: web::Data<HealthThresholds>,
+    cache: web::Data<HealthCache>,
+    access: web::Data<AccessControl>,
+    config: web::Data<ServerConfig>,
+) -> impl Responder {
+    let qs = QString::from(req.query_string());
+    //state= only lists feeds in that state, the counts always cover everything
+    let state = match qs.get("state") {
+        Some(state) => match HealthState::from_name(state) {
+            Some(state) => Some(state),
+            None => return bad_request("state must be healthy, degraded or dead"),
+        },
+        None => None,
+    };
 
-                vecoftimes.push(feedtime);
+    let now = now_millis();
+    let checked = match cache.get(now) {
+        Some(checked) => checked,
+        None => {
+            let mut con = match redis_connection() {
+                Ok(con) => con,
+                Err(e) => return feed_error_response(&e),
+            };
+            match check_all(&mut con, &thresholds, now) {
+                Ok(feeds) => cache.store(now, feeds),
+                Err(e) => return feed_error_response(&FeedError::backend(e)),
             }
         }
+    };
+    //restricted feeds are left out unless an admin is asking
+    let feeds: Vec<&FeedHealth> = if is_admin(&req, &config) {
+        checked.iter().collect()
+    } else {
+        let hidden = access.hidden(None);
+        checked
+            .iter()
+            .filter(|feed| !hidden.contains(&feed.feed))
+            .collect()
+    };
+
+    let count = |state: HealthState| feeds.iter().filter(|feed| feed.state == state).count();
+    let summary = HealthSummary {
+        healthy: count(HealthState::Healthy),
+        degraded: count(HealthState::Degraded),
+        dead: count(HealthState::Dead),
+        feeds: feeds
+            .iter()
+            .filter(|feed| state.map_or(true, |state| feed.state == state))
+            .map(|feed| (*feed).clone())
+            .collect(),
+    };
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "application/json"))
+        .body(format!("{}\n", serde_json::to_string(&summary).unwrap()))
+}
+
+async fn metricsendpoint(req: HttpRequest, config: web::Data<ServerConfig>) -> impl Responder {
+    if !is_admin(&req, &config) {
+        return request_error(
+            StatusCode::FORBIDDEN,
+            "admin_required",
+            "Admin key required",
+        );
+    }
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "text/plain; version=0.0.4"))
+        .body(metrics().render())
+}
+
+//counts a finished request and how long it took
+//requests that weren't answered from a feed's snapshot, see served, are counted under "other"
+fn record_request(req: &HttpRequest, status: StatusCode, elapsed: Duration) {
+    let route = match req.match_pattern() {
+        Some(pattern) if pattern.len() > 1 => pattern.trim_end_matches('/').to_string(),
+        Some(pattern) => pattern,
+        None => String::from("unmatched"),
+    };
+    let feed = match req.extensions().get::<ServedFeed>() {
+        Some(ServedFeed(feed)) => feed.clone(),
+        None => String::from("other"),
+    };
+    metrics().inc(
+        "kactus_http_requests_total",
+        &[
+            ("route", &route),
+            ("feed", &feed),
+            ("status", status.as_str()),
+        ],
+        1.0,
+    );
+    metrics().observe(
+        "kactus_http_request_duration_seconds",
+        &[("route", &route)],
+        elapsed.as_secs_f64(),
+    );
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+//timeofcache may be sent in seconds or milliseconds, anything this small must be seconds
+fn as_millis(time: u64) -> u64 {
+    if time < 100_000_000_000 {
+        time * 1000
+    } else {
+        time
+    }
+}
+
+//query parameters that change the body sent for the same snapshot
+const VARIANT_PARAMETERS: [&str; 13] = [
+    "route_id",
+    "route",
+    "trip_id",
+    "stop_id",
+    "vehicle_id",
+    "direction_id",
+    "agency_id",
+    "bbox",
+    "lat",
+    "lon",
+    "radius",
+    "since",
+    "format",
+];
+
+//what the query asks for on top of the snapshots, so a filtered or diffed body gets its own etag
+fn variant(qs: &QString) -> u64 {
+    let mut hasher = metrohash::MetroHash64::new();
+    for (name, value) in qs.to_pairs() {
+        if VARIANT_PARAMETERS.contains(&name) {
+            hasher.write(name.as_bytes());
+            hasher.write(&[0]);
+            hasher.write(value.as_bytes());
+            hasher.write(&[0]);
+        }
+    }
+    hasher.finish()
+}
+
+//weak because the same snapshot is sent with different content encodings
+fn etag(snapshot: &Snapshot, qs: &QString) -> String {
+    let mut hasher = metrohash::MetroHash64::new();
+    hasher.write_u64(snapshot.hash);
+    hasher.write_u64(variant(qs));
+    format!("W/\"{:x}\"", hasher.finish())
+}
+
+fn etag_matches(if_none_match: &header::HeaderValue, etag: &str) -> bool {
+    let opaque = etag.trim_start_matches("W/");
+    if_none_match
+        .to_str()
+        .unwrap_or("")
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == opaque)
+}
+
+fn not_modified(req: &HttpRequest, snapshot: &Snapshot, etag: &str) -> bool {
+    if let Some(if_none_match) = req.headers().get("If-None-Match") {
+        return snapshot.proto.is_ok() && etag_matches(if_none_match, etag);
+    }
+    if let Some(if_modified_since) = req.headers().get("If-Modified-Since") {
+        let since = if_modified_since
+            .to_str()
+            .ok()
+            .and_then(|since| since.parse::<HttpDate>().ok());
+        if let Some(since) = since {
+            let since = SystemTime::from(since)
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or(0);
+            return snapshot.time / 1000 <= since;
+        }
+    }
+    false
+}
+
+//private responses are only kept by the client's own cache, see AccessControl::is_private
+//the next fetch is due one interval after this one, so the snapshot is good until then
+fn fresh_for(snapshot: &Snapshot, now: u64) -> Option<u64> {
+    let age = now.saturating_sub(snapshot.time);
+    snapshot
+        .fetch_interval
+        .map(|fetch_interval| ((fetch_interval * 1000.0) as u64).saturating_sub(age) / 1000)
+}
+
+fn cache_control(fresh_for: Option<u64>, private: bool) -> String {
+    match fresh_for {
+        Some(seconds) => format!(
+            "{}, max-age={}",
+            if private { "private" } else { "public" },
+            seconds
+        ),
+        None => String::from("no-cache"),
+    }
+}
+
+fn cache_headers(
+    response: &mut HttpResponseBuilder,
+    snapshot: &Snapshot,
+    etag: &str,
+    private: bool,
+) {
+    let now = now_millis();
+    let age = now.saturating_sub(snapshot.time);
+
+    if snapshot.proto.is_ok() {
+        response.insert_header(("ETag", etag));
+    }
+    response.insert_header((
+        "Last-Modified",
+        HttpDate::from(UNIX_EPOCH + Duration::from_millis(snapshot.time)),
+    ));
+    let cache_control = cache_control(fresh_for(snapshot, now), private);
+    response.insert_header(("Cache-Control", cache_control));
+    response.insert_header(("X-Feed-Age", format!("{:.3}", age as f64 / 1000.0)));
+    response.insert_header(("X-Feed-Fetched", snapshot.time));
+    if let Some(timestamp) = snapshot.header_timestamp() {
+        response.insert_header(("X-Feed-Timestamp", timestamp));
+    }
+}
+
+//NOT PROTOBUF this is the list of available agencies
+async fn gtfsrttimes(
+    req: HttpRequest,
+    access: web::Data<AccessControl>,
+    thresholds: web::Data<HealthThresholds>,
+) -> impl Responder {
+    let mut con = match redis_connection() {
+        Ok(con) => con,
+        Err(e) => return feed_error_response(&e),
+    };
+
+    let startiterator = Instant::now();
+
+    let query = CatalogQuery {
+        limit: usize::MAX,
+        hidden: access.hidden(request_api_key(&req).as_ref()),
+        ..CatalogQuery::default()
+    };
+    let listed = catalog::list(&mut con, &query, &thresholds, now_millis());
+    let vecoftimes: Vec<FeedTimes> = match listed {
+        Ok(page) => page
+            .feeds
+            .iter()
+            .map(|status| FeedTimes {
+                feed: status.feed.clone(),
+                vehicles: status.category("vehicles").map(|category| category.fetched),
+                trips: status.category("trips").map(|category| category.fetched),
+                alerts: status.category("alerts").map(|category| category.fetched),
+            })
+            .collect(),
         Err(e) => {
             println!("Error: {:?}", e);
-            return HttpResponse::InternalServerError()
-                .insert_header(("Content-Type", "text/plain"))
-                .body(format!("Error: {}\n", e));
+            return feed_error_response(&FeedError::backend(e));
         }
     };
 
     let finishiterator = startiterator.elapsed();
 
-    println!("reading file took {:#?}", finishiterator);
+    println!("reading catalog took {:#?}", finishiterator);
 
     let json = serde_json::to_string(&vecoftimes).unwrap();
 
@@ -249,193 +887,866 @@ async fn gtfsrttimes(_req: HttpRequest) -> impl Responder {
         .body(format!("{}\n", json))
 }
 
-async fn gtfsrttojson(req: HttpRequest) -> impl Responder {
-    let redisclient = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
-    let mut con = redisclient.get_connection().unwrap();
+const DEFAULT_CATALOG_LIMIT: usize = 100;
+const MAX_CATALOG_LIMIT: usize = 1000;
+
+fn parse_limit(qs: &QString, default: usize, max: usize) -> Result<usize, String> {
+    match qs.get("limit") {
+        Some(limit) => match limit.parse::<usize>() {
+            Ok(limit) if limit > 0 => Ok(limit.min(max)),
+            _ => Err(String::from("limit must be a positive number")),
+        },
+        None => Ok(default),
+    }
+}
+
+fn catalog_query(qs: &QString) -> Result<CatalogQuery, String> {
+    let limit = parse_limit(qs, DEFAULT_CATALOG_LIMIT, MAX_CATALOG_LIMIT)?;
+    let max_age = parse_max_age(qs)?;
+    let stale = match qs.get("stale") {
+        Some("true") => Some(true),
+        Some("false") => Some(false),
+        Some(_) => return Err(String::from("stale must be true or false")),
+        None => None,
+    };
+    Ok(CatalogQuery {
+        prefix: qs.get("prefix").map(|prefix| prefix.to_string()),
+        category: qs.get("category").map(|category| category.to_string()),
+        max_age,
+        stale,
+        after: qs.get("after").map(|after| after.to_string()),
+        limit,
+        ..CatalogQuery::default()
+    })
+}
+
+async fn feedcatalog(
+    req: HttpRequest,
+    access: web::Data<AccessControl>,
+    thresholds: web::Data<HealthThresholds>,
+) -> impl Responder {
+    let qs = QString::from(req.query_string());
+    let query = match catalog_query(&qs) {
+        Ok(query) => CatalogQuery {
+            hidden: access.hidden(request_api_key(&req).as_ref()),
+            ..query
+        },
+        Err(e) => return bad_request(e),
+    };
+
+    let mut con = match redis_connection() {
+        Ok(con) => con,
+        Err(e) => return feed_error_response(&e),
+    };
+    match catalog::list(&mut con, &query, &thresholds, now_millis()) {
+        Ok(page) => HttpResponse::Ok()
+            .insert_header(("Content-Type", "application/json"))
+            .body(format!("{}\n", serde_json::to_string(&page).unwrap())),
+        Err(e) => feed_error_response(&FeedError::backend(e)),
+    }
+}
+
+async fn gtfsrttojson(
+    req: HttpRequest,
+    cache: web::Data<SnapshotCache>,
+    descriptor: web::Data<FeedDescriptor>,
+    access: web::Data<AccessControl>,
+) -> impl Responder {
     let qs = QString::from(req.query_string());
     let feed = match qs.get("feed") {
         Some(feed) => feed.to_string(),
-        None => {
-            return HttpResponse::NotFound()
-                .insert_header(("Content-Type", "text/plain"))
-                .body("Error: No feed specified\n")
-        }
+        None => return bad_request("No feed specified"),
     };
     let category = match qs.get("category") {
         Some(category) => category.to_string(),
-        None => {
-            return HttpResponse::NotFound()
-                .insert_header(("Content-Type", "text/plain"))
-                .body("Error: No category specified\n")
+        None => return bad_request("No category specified"),
+    };
+    if let Some(response) = restricted_response(&req, &access, &feed) {
+        return response;
+    }
+    //raw=true is the old way of asking for debug output
+    let format = match qs.get("raw") {
+        Some("true") => OutputFormat::Debug,
+        _ => match parse_format(&qs, &category, OutputFormat::Json) {
+            Ok(format) => format,
+            Err(e) => return bad_request(e),
+        },
+    };
+    formatted_feed(&req, &qs, &feed, &category, format, &cache, &descriptor)
+}
+
+//format= with the formats that only work for some categories turned away
+fn parse_format(
+    qs: &QString,
+    category: &str,
+    default: OutputFormat,
+) -> Result<OutputFormat, String> {
+    match OutputFormat::from_query(qs, default)? {
+        OutputFormat::GeoJson if category != "vehicles" => {
+            Err(String::from("geojson is only available for vehicles"))
         }
+        OutputFormat::Csv if category != "vehicles" && category != "trips" => {
+            Err(String::from("csv is only available for vehicles and trips"))
+        }
+        format => Ok(format),
+    }
+}
+
+//a feed, or several merged, in any format but the cached protobuf
+fn formatted_feed(
+    req: &HttpRequest,
+    qs: &QString,
+    feed: &str,
+    category: &str,
+    format: OutputFormat,
+    cache: &SnapshotCache,
+    descriptor: &FeedDescriptor,
+) -> HttpResponse {
+    let filter = match EntityFilter::from_query(qs, category) {
+        Ok(filter) => filter,
+        Err(e) => return bad_request(e),
     };
-    let usejson = match qs.get("raw") {
-        Some(raw) => {
-            if raw == "true" {
-                false
-            } else {
-                true
-            }
+    let max_age = match parse_max_age(qs) {
+        Ok(max_age) => max_age,
+        Err(e) => return bad_request(e),
+    };
+    let mut con = match redis_connection() {
+        Ok(con) => con,
+        Err(e) => return feed_error_response(&e),
+    };
+    let proto = if feed.contains(',') || category == "all" {
+        let merged = MergedSnapshots::load(cache, &mut con, feed, category, max_age)
+            .and_then(|snapshots| {
+                served(req, feed);
+                snapshots.message()
+            });
+        match merged {
+            Ok(merged) if filter.is_empty() => merged,
+            Ok(merged) => filter.apply(&merged),
+            Err(e) => return feed_error_response(&e),
         }
-        None => true,
-    };
-    let doesexist = con.get::<String, u64>(format!("gtfsrttime|{}|{}", feed, category));
-    if doesexist.is_err() {
-        return HttpResponse::InternalServerError()
-            .insert_header(("Content-Type", "text/plain"))
-            .body(format!("Error in connecting to redis\n"));
-    }
-    let data = con.get::<String, Vec<u8>>(format!("gtfsrt|{}|{}", feed, category));
-    if data.is_err() {
-        return HttpResponse::InternalServerError()
-            .insert_header(("Content-Type", "text/plain"))
-            .body(format!("Error: {:?}\n", data.unwrap()));
-    }
-    let proto = parse_protobuf_message(&data.unwrap());
-    if proto.is_err() {
-        println!("Error parsing protobuf");
-        println!("{:#?}", proto);
-        return HttpResponse::InternalServerError().body(format!("{:#?}", proto));
-    }
-    let proto = match qs.get("route") {
-        Some(route) => {
-            let mut filtered_message = FeedMessage::default();
-            filtered_message.header = FeedHeader {
-                gtfs_realtime_version: "2.0".to_string(),
-                incrementality: Some(0),
-                timestamp: proto.as_ref().unwrap().header.timestamp.clone(),
-            };
+    } else {
+        let snapshot = match cache.snapshot(&mut con, feed, category) {
+            Ok(snapshot) => snapshot,
+            Err(e) => return feed_error_response(&e),
+        };
+        served(req, feed);
+        if let Err(e) = snapshot
+            .check_age(feed, category, max_age, now_millis())
+            .and_then(|_| snapshot.message(feed, category))
+        {
+            println!("{}", e);
+            return feed_error_response(&e);
+        }
+        if format == OutputFormat::Json && filter.is_empty() {
+            return HttpResponse::Ok()
+                .insert_header(("Content-Type", "application/json"))
+                .body(snapshot.json().unwrap().to_string());
+        }
+        snapshot.filtered(&filter).unwrap()
+    };
 
-            for entity in proto.unwrap().entity {
-                let mut filtered_entity = FeedEntity::default();
-                filtered_entity.id = entity.id.clone();
-                if entity.trip_update.is_some()
-                    && entity.trip_update.as_ref().unwrap().trip.route_id() == route
-                {
-                    filtered_entity.trip_update = entity.trip_update.clone();
-                }
-                if entity.vehicle.is_some()
-                    && entity.vehicle.as_ref().unwrap().trip.is_some()
-                    && entity
-                        .vehicle
-                        .as_ref()
-                        .unwrap()
-                        .trip
-                        .as_ref()
-                        .unwrap()
-                        .route_id()
-                        == route
-                {
-                    filtered_entity.vehicle = entity.vehicle.clone();
-                }
-                if entity.alert.is_some() {
-                    let mut informed_entities: Vec<EntitySelector> = Vec::new();
-                    for informed_entity in &entity.alert.as_ref().unwrap().informed_entity {
-                        if informed_entity.route_id() == route {
-                            informed_entities.push(informed_entity.clone());
-                        }
-                    }
-                    if filtered_entity.alert.is_none() {
-                        filtered_entity.alert = entity.alert.clone();
-                    }
-                    filtered_entity.alert.as_mut().unwrap().informed_entity = informed_entities;
-                }
-                println!("{:?}", filtered_entity);
-                if filtered_entity.trip_update.is_some()
-                    || filtered_entity.vehicle.is_some()
-                    || filtered_entity.alert.is_some()
-                    || filtered_entity.shape.is_some()
-                {
-                    filtered_message.entity.push(filtered_entity);
-                }
-            }
-            println!("{:?}", filtered_message);
-            filtered_message
+    formatted_response(format, category, &proto, descriptor)
+}
+
+//html page with a feed's vehicles, trip updates and alerts for people debugging it
+async fn viewer(
+    req: HttpRequest,
+    cache: web::Data<SnapshotCache>,
+    access: web::Data<AccessControl>,
+    config: web::Data<ServerConfig>,
+    thresholds: web::Data<HealthThresholds>,
+) -> impl Responder {
+    let qs = QString::from(req.query_string());
+    let feed = match qs.get("feed") {
+        Some(feed) => feed,
+        None => return bad_request("No feed specified"),
+    };
+    let category = match qs.get("category") {
+        Some(category) => category,
+        None => return bad_request("No category specified"),
+    };
+    if let Some(response) = restricted_response(&req, &access, feed) {
+        return response;
+    }
+    let filter = match EntityFilter::from_query(&qs, &category) {
+        Ok(filter) => filter,
+        Err(e) => return bad_request(e),
+    };
+
+    let mut con = match redis_connection() {
+        Ok(con) => con,
+        Err(e) => return feed_error_response(&e),
+    };
+    let snapshot = match cache.snapshot(&mut con, feed, category) {
+        Ok(snapshot) => snapshot,
+        Err(e) => return feed_error_response(&e),
+    };
+    served(&req, feed);
+    let message = match snapshot.filtered(&filter) {
+        Some(message) => message,
+        None => return invalid_snapshot_response(feed, category, &[&snapshot]),
+    };
+
+    let now = now_millis();
+    let status = catalog::status(&mut con, feed, &thresholds, now)
+        .ok()
+        .flatten();
+    let failed = match is_admin(&req, &config) {
+        true => con
+            .get::<String, Option<String>>(format!("gtfsrtfailed|{}|{}", feed, category))
+            .ok()
+            .flatten()
+            .and_then(|failed| serde_json::from_str::<FailedPayload>(&failed).ok()),
+        false => None,
+    };
+
+    let page = ViewerPage {
+        feed,
+        category,
+        message: &message,
+        fetched: snapshot.time,
+        status: status.as_ref(),
+        failed: failed.as_ref(),
+        now_millis: now,
+    };
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "text/html; charset=utf-8"))
+        .body(page.render())
+}
+
+//vector tile with the vehicles of every feed, /tiles/vehicles/{z}/{x}/{y}.mvt
+async fn vehicletile(
+    req: HttpRequest,
+    path: web::Path<(u32, u32, String)>,
+    all_vehicles: web::Data<AllVehicles>,
+    tile_cache: web::Data<TileCache>,
+    access: web::Data<AccessControl>,
+) -> impl Responder {
+    let (z, x, tile) = path.into_inner();
+    let tile = match tile
+        .strip_suffix(".mvt")
+        .and_then(|y| y.parse::<u32>().ok())
+        .and_then(|y| TileId::new(z, x, y))
+    {
+        Some(tile) => tile,
+        None => return request_error(StatusCode::NOT_FOUND, "not_found", "No such tile"),
+    };
+
+    let hidden = access.hidden(request_api_key(&req).as_ref());
+    let now = now_millis();
+    let encoded = tile_cache.get_or_render(tile, &hidden, now, || {
+        let feeds = all_vehicles.current(&hidden, now);
+        vehicle_tile(tile, &in_envelope(&feeds, &tile.envelope()))
+    });
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "application/vnd.mapbox-vector-tile"))
+        //vehicles move all the time, but a map panning around shouldn't ask for the same tile twice
+        //private since restricted feeds are only in the tiles of keys that may see them
+        .insert_header(("Cache-Control", "private, max-age=5"))
+        .body(encoded.to_vec())
+}
+
+const DEFAULT_VEHICLE_LIMIT: usize = 100;
+const MAX_VEHICLE_LIMIT: usize = 1000;
+//meters
+const DEFAULT_NEARBY_RADIUS: f64 = 500.0;
+const MAX_NEARBY_RADIUS: f64 = 10_000.0;
+
+#[derive(Serialize)]
+struct VehicleResults {
+    vehicles: Vec<VehicleMatch>,
+}
+
+fn vehicle_results(vehicles: Vec<VehicleMatch>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "application/json"))
+        .body(format!(
+            "{}\n",
+            serde_json::to_string(&VehicleResults { vehicles }).unwrap()
+        ))
+}
+
+//lat, lon, radius and limit for /vehicles/nearby
+fn nearby_query(qs: &QString) -> Result<(f64, f64, f64, usize), String> {
+    let lat = parse_number(qs, "lat")?.ok_or_else(|| String::from("lat is required"))?;
+    let lon = parse_number(qs, "lon")?.ok_or_else(|| String::from("lon is required"))?;
+    if !(-90.0..=90.0).contains(&lat) {
+        return Err(String::from("lat must be between -90 and 90"));
+    }
+    if !(-180.0..=180.0).contains(&lon) {
+        return Err(String::from("lon must be between -180 and 180"));
+    }
+    let radius = match parse_number(qs, "radius")? {
+        Some(radius) if radius > 0.0 => radius.min(MAX_NEARBY_RADIUS),
+        Some(_) => return Err(String::from("radius must be a positive number of meters")),
+        None => DEFAULT_NEARBY_RADIUS,
+    };
+    let limit = parse_limit(qs, DEFAULT_VEHICLE_LIMIT, MAX_VEHICLE_LIMIT)?;
+    Ok((lat, lon, radius, limit))
+}
+
+//vehicles of every feed around a point, closest first
+async fn vehiclesnearby(
+    req: HttpRequest,
+    all_vehicles: web::Data<AllVehicles>,
+    access: web::Data<AccessControl>,
+) -> impl Responder {
+    let qs = QString::from(req.query_string());
+    let (lat, lon, radius, limit) = match nearby_query(&qs) {
+        Ok(parsed) => parsed,
+        Err(e) => return bad_request(e),
+    };
+
+    let feeds = all_vehicles.current(&access.hidden(request_api_key(&req).as_ref()), now_millis());
+    vehicle_results(
+        nearby(&feeds, lat, lon, radius, limit)
+            .iter()
+            .map(|(found, distance)| found.to_match(Some(*distance)))
+            .collect(),
+    )
+}
+
+//vehicles of every feed with a label, like the fleet number painted on a bus
+async fn vehiclesearch(
+    req: HttpRequest,
+    all_vehicles: web::Data<AllVehicles>,
+    access: web::Data<AccessControl>,
+) -> impl Responder {
+    let qs = QString::from(req.query_string());
+    let label = match qs.get("label") {
+        Some(label) if !label.trim().is_empty() => label,
+        _ => return bad_request("label is required"),
+    };
+    let limit = match parse_limit(&qs, DEFAULT_VEHICLE_LIMIT, MAX_VEHICLE_LIMIT) {
+        Ok(limit) => limit,
+        Err(e) => return bad_request(e),
+    };
+
+    let feeds = all_vehicles.current(&access.hidden(request_api_key(&req).as_ref()), now_millis());
+    vehicle_results(
+        with_label(&feeds, label)
+            .iter()
+            .take(limit)
+            .map(|found| found.to_match(None))
+            .collect(),
+    )
+}
+
+fn formatted_response(
+    format: OutputFormat,
+    category: &str,
+    proto: &FeedMessage,
+    descriptor: &FeedDescriptor,
+) -> HttpResponse {
+    let rendered = match format {
+        OutputFormat::Protobuf => {
+            return HttpResponse::Ok()
+                .insert_header(("Content-Type", "application/x-google-protobuf"))
+                .body(proto.encode_to_vec())
+        }
+        OutputFormat::Json => {
+            return HttpResponse::Ok()
+                .insert_header(("Content-Type", "application/json"))
+                .body(serde_json::to_string(proto).unwrap())
+        }
+        OutputFormat::Debug => return HttpResponse::Ok().body(format!("{:#?}", proto)),
+        OutputFormat::GeoJson => {
+            return HttpResponse::Ok()
+                .insert_header(("Content-Type", "application/geo+json"))
+                .body(vehicles_geojson(proto).to_string())
+        }
+        OutputFormat::Csv if category == "trips" => {
+            trip_updates_csv(proto).map(|csv| ("text/csv; charset=utf-8", csv))
         }
-        None => proto.unwrap(),
+        OutputFormat::Csv => vehicles_csv(proto).map(|csv| ("text/csv; charset=utf-8", csv)),
+        OutputFormat::ProtoJson => descriptor
+            .to_proto_json(proto)
+            .map(|json| ("application/json", json)),
+        OutputFormat::Text => descriptor.to_text(proto).map(|text| ("text/plain", text)),
     };
+    match rendered {
+        Ok((content_type, body)) => HttpResponse::Ok()
+            .insert_header(("Content-Type", content_type))
+            .body(body),
+        Err(e) => request_error(StatusCode::INTERNAL_SERVER_ERROR, "internal", &e),
+    }
+}
 
-    if usejson {
-        let protojson = serde_json::to_string(&proto).unwrap();
-        HttpResponse::Ok()
-            .insert_header(("Content-Type", "application/json"))
-            .body(protojson)
-    } else {
-        let protojson = format!("{:#?}", proto);
-        HttpResponse::Ok().body(protojson)
+fn is_admin(req: &HttpRequest, config: &ServerConfig) -> bool {
+    match (&config.admin_key, req.headers().get("X-Admin-Key")) {
+        (Some(admin_key), Some(given)) => constant_time_eq(given.as_bytes(), admin_key.as_bytes()),
+        _ => false,
     }
 }
 
+//paths that are never limited, for monitoring and admins
+fn is_exempt(path: &str) -> bool {
+    path == "/metrics" || path == "/health" || path.starts_with("/admin/")
+}
+
+fn denied_response(denied: Denied) -> HttpResponse {
+    let mut response = match denied {
+        Denied::MissingKey | Denied::UnknownKey => HttpResponse::Unauthorized(),
+        Denied::RateLimited(_) | Denied::QuotaExceeded(_) => HttpResponse::TooManyRequests(),
+    };
+    if let Some(wait) = denied.retry_after() {
+        response.insert_header(("Retry-After", (wait.as_secs_f64().ceil() as u64).max(1)));
+    }
+    response
+        .insert_header(("Content-Type", "application/json"))
+        .body(format!("{}\n", error_body(denied.code(), denied.message())))
+}
+
+#[derive(Serialize)]
+struct UsageResponse {
+    name: String,
+    date: String,
+    total: u64,
+    feeds: HashMap<String, u64>,
+}
+
+//requests an API key made on a day, by feed, admin only
+async fn keyusage(req: HttpRequest, config: web::Data<ServerConfig>) -> impl Responder {
+    if !is_admin(&req, &config) {
+        return request_error(
+            StatusCode::FORBIDDEN,
+            "admin_required",
+            "Admin key required",
+        );
+    }
+    let qs = QString::from(req.query_string());
+    let name = match qs.get("name") {
+        Some(name) => name,
+        None => return bad_request("No key name specified"),
+    };
+    let date = match qs.get("date") {
+        Some(date) => date.to_string(),
+        None => chrono::Utc::now().format("%Y-%m-%d").to_string(),
+    };
+
+    let mut con = match redis_connection() {
+        Ok(con) => con,
+        Err(e) => return feed_error_response(&e),
+    };
+    match usage(&mut con, name, &date) {
+        Ok(feeds) => {
+            let usage = UsageResponse {
+                name: name.to_string(),
+                date,
+                total: feeds.values().sum(),
+                feeds,
+            };
+            HttpResponse::Ok()
+                .insert_header(("Content-Type", "application/json"))
+                .body(format!("{}\n", serde_json::to_string(&usage).unwrap()))
+        }
+        Err(e) => feed_error_response(&FeedError::backend(e)),
+    }
+}
+
+#[derive(Serialize)]
+pub struct FailedPayloadResponse {
+    #[serde(flatten)]
+    failed: FailedPayload,
+    stored_bytes: usize,
+}
+
+//last rejected or undecodable payload for a feed, admin only
+async fn failedpayload(req: HttpRequest, config: web::Data<ServerConfig>) -> impl Responder {
+    if !is_admin(&req, &config) {
+        return request_error(
+            StatusCode::FORBIDDEN,
+            "admin_required",
+            "Admin key required",
+        );
+    }
+    let mut con = match redis_connection() {
+        Ok(con) => con,
+        Err(e) => return feed_error_response(&e),
+    };
+    let qs = QString::from(req.query_string());
+    let feed = match qs.get("feed") {
+        Some(feed) => feed,
+        None => return bad_request("No feed specified"),
+    };
+    let category = match qs.get("category") {
+        Some(category) => category,
+        None => return bad_request("No category specified"),
+    };
+    let failed = con.get::<String, Option<String>>(format!("gtfsrtfailed|{}|{}", feed, category));
+    let failed = match failed {
+        Ok(Some(failed)) => failed,
+        Ok(None) => {
+            return request_error(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "No failed payload recorded",
+            )
+        }
+        Err(e) => return feed_error_response(&FeedError::backend(e)),
+    };
+    let bytes = con
+        .get::<String, Vec<u8>>(format!("gtfsrtfailedbytes|{}|{}", feed, category))
+        .unwrap_or_default();
+    if qs.get("raw") == Some("true") {
+        return HttpResponse::Ok()
+            .insert_header(("Content-Type", "application/octet-stream"))
+            .body(bytes);
+    }
+    let failed = match serde_json::from_str::<FailedPayload>(&failed) {
+        Ok(failed) => failed,
+        Err(e) => {
+            return request_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                &e.to_string(),
+            )
+        }
+    };
+    let json = serde_json::to_string(&FailedPayloadResponse {
+        failed,
+        stored_bytes: bytes.len(),
+    })
+    .unwrap();
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "application/json"))
+        .body(format!("{}\n", json))
+}
+
 async fn gtfsrtws(
     req: HttpRequest,
     stream: web::Payload,
+    cache: web::Data<SnapshotCache>,
+    updates: web::Data<FeedUpdates>,
+    access: web::Data<AccessControl>,
 ) -> Result<HttpResponse, actix_web::Error> {
+    //?feed=&category= subscribes right away, more can be added with control messages
+    let qs = QString::from(req.query_string());
+    let initial = match (qs.get("feed"), qs.get("category")) {
+        (Some(feed), Some(category)) => Some((feed.to_string(), category.to_string())),
+        _ => None,
+    };
+    let api_key = request_api_key(&req);
+    ws::start(
+        FeedSocket::new(
+            updates.into_inner(),
+            cache.into_inner(),
+            access.into_inner(),
+            api_key,
+            initial,
+        ),
+        &req,
+        stream,
+    )
+}
+
+async fn gtfsrtsse(
+    req: HttpRequest,
+    cache: web::Data<SnapshotCache>,
+    updates: web::Data<FeedUpdates>,
+    access: web::Data<AccessControl>,
+) -> impl Responder {
     let qs = QString::from(req.query_string());
     let feed = match qs.get("feed") {
-        Some(feed) => feed.to_string(),
-        None => {
-            return Ok(HttpResponse::NotFound()
-                .insert_header(("Content-Type", "text/plain"))
-                .body("Error: No feed specified\n"))
-        }
+        Some(feed) => feed,
+        None => return bad_request("No feed specified"),
     };
     let category = match qs.get("category") {
-        Some(category) => category.to_string(),
-        None => {
-            return Ok(HttpResponse::NotFound()
-                .insert_header(("Content-Type", "text/plain"))
-                .body("Error: No category specified\n"))
-        }
+        Some(category) => category,
+        None => return bad_request("No category specified"),
     };
-    let suicidebutton = match qs.get("suicidebutton") {
-        Some(_) => true,
-        None => false,
+    if let Some(response) = restricted_response(&req, &access, feed) {
+        return response;
+    }
+    let data = match EventData::from_query(&qs) {
+        Ok(data) => data,
+        Err(e) => return bad_request(e),
     };
-    let resp = ws::start(
-        GtfsWs {
-            feed,
-            category,
-            suicidebutton,
-        },
-        &req,
-        stream,
-    );
-    println!("{:?}", resp);
-    resp
+
+    //subscribe before loading so nothing ingested in between is missed
+    let receiver = updates.subscribe(feed, category);
+    let current =
+        match redis_connection().and_then(|mut con| cache.snapshot(&mut con, feed, category)) {
+            Ok(snapshot) => snapshot,
+            Err(e) => return feed_error_response(&e),
+        };
+    served(&req, feed);
+    //browsers send the id of the last event they saw when an EventSource reconnects
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.trim().parse::<u64>().ok());
+
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "text/event-stream"))
+        .insert_header(("Cache-Control", "no-cache"))
+        //compressing would hold events back until a block fills up
+        .insert_header(("Content-Encoding", "identity"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(event_stream(
+            feed.to_string(),
+            category.to_string(),
+            data,
+            receiver,
+            current,
+            last_event_id,
+        ))
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let port = arguments::parse(std::env::args())
-        .unwrap()
-        .get::<u16>("port")
-        .unwrap_or_else(|| 54105);
+    let arguments = arguments::parse(std::env::args()).unwrap();
+    let port = arguments.get::<u16>("port").unwrap_or_else(|| 54105);
+
+    let config = ServerConfig {
+        admin_key: arguments
+            .get::<String>("admin_key")
+            .or_else(|| std::env::var("KACTUS_ADMIN_KEY").ok()),
+    };
 
-    let builder = HttpServer::new(|| {
+    //shared between workers so each snapshot is only compressed once
+    let cache = web::Data::new(SnapshotCache::new());
+    let updates = web::Data::new(FeedUpdates::new());
+    let all_vehicles = web::Data::new(AllVehicles::new());
+    watch(
+        updates.clone().into_inner(),
+        cache.clone().into_inner(),
+        all_vehicles.clone().into_inner(),
+    );
+
+    {
+        let redisclient = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let mut con = redisclient.get_connection().unwrap();
+        match catalog::backfill(&mut con) {
+            Ok(0) => {}
+            Ok(count) => println!("Added {} existing feeds to the catalog", count),
+            Err(e) => println!("Could not fill the catalog from existing keys: {}", e),
+        }
+    }
+
+    let descriptor = match arguments.get::<String>("proto") {
+        Some(path) => FeedDescriptor::load(&path).unwrap(),
+        None => FeedDescriptor::embedded().unwrap(),
+    };
+    let descriptor = web::Data::new(descriptor);
+
+    let thresholds = match arguments.get::<String>("health_thresholds") {
+        Some(path) => HealthThresholds::load(&path).unwrap(),
+        None => HealthThresholds::default(),
+    };
+    let thresholds = web::Data::new(thresholds);
+    let health_cache = web::Data::new(HealthCache::default());
+    let tile_cache = web::Data::new(TileCache::default());
+
+    let api_keys = match arguments.get::<String>("api_keys") {
+        Some(path) => load_api_keys(&path).unwrap(),
+        None => HashMap::new(),
+    };
+    //feeds whose licence only allows serving them to consumers with a key that lists them
+    let restricted = match arguments.get::<String>("restricted_feeds") {
+        Some(path) => load_restricted_feeds(&path).unwrap(),
+        None => HashSet::new(),
+    };
+    let anonymous = match (
+        arguments.get::<f64>("anonymous_rate"),
+        arguments.get::<f64>("anonymous_burst"),
+    ) {
+        (Some(rate), burst) => Some((rate, burst.unwrap_or(rate))),
+        (None, _) => None,
+    };
+    //comma separated addresses of reverse proxies allowed to say who the client is
+    let trusted_proxies: HashSet<IpAddr> = arguments
+        .get::<String>("trusted_proxies")
+        .map(|list| {
+            list.split(',')
+                .map(|address| address.trim())
+                .filter(|address| !address.is_empty())
+                .map(|address| {
+                    address
+                        .parse::<IpAddr>()
+                        .expect("trusted_proxies must be IP addresses")
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    //shared between workers so rate limits hold across all of them
+    let access = web::Data::new(AccessControl::new(
+        api_keys,
+        restricted,
+        arguments.get::<bool>("require_api_key").unwrap_or(false),
+        anonymous,
+        trusted_proxies,
+    ));
+    flush_usage(access.clone().into_inner());
+
+    //where to listen, from flags or the environment so service files can set them
+    let setting = |name: &str, env: &str| {
+        arguments
+            .get::<String>(name)
+            .or_else(|| std::env::var(env).ok())
+    };
+    let addresses = |list: Option<String>| -> Vec<String> {
+        list.map(|list| {
+            list.split(',')
+                .map(|address| address.trim().to_string())
+                .filter(|address| !address.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+    };
+    let mut bind = addresses(setting("bind", "KACTUS_BIND"));
+    let tls_bind = addresses(setting("tls_bind", "KACTUS_TLS_BIND"));
+    let unix_socket = setting("unix_socket", "KACTUS_UNIX_SOCKET");
+    //without any of them, listen where the server always did
+    if bind.is_empty() && tls_bind.is_empty() && unix_socket.is_none() {
+        bind.push(format!("127.0.0.1:{}", port));
+    }
+    let workers = match setting("workers", "KACTUS_WORKERS") {
+        Some(workers) => workers.parse::<usize>().expect("workers must be a number"),
+        None => 4,
+    };
+    //the certificate is reloaded when renewed, so it's loaded once for every worker
+    let tls_config = if tls_bind.is_empty() {
+        None
+    } else {
+        let cert = setting("tls_cert", "KACTUS_TLS_CERT").expect("tls_bind needs tls_cert");
+        let key = setting("tls_key", "KACTUS_TLS_KEY").expect("tls_bind needs tls_key");
+        Some(tls::server_config(&cert, &key).unwrap())
+    };
+
+    let mut builder = HttpServer::new(move || {
+        let access_check = access.clone();
         App::new()
+            .app_data(web::Data::new(config.clone()))
+            .app_data(cache.clone())
+            .app_data(updates.clone())
+            .app_data(all_vehicles.clone())
+            .app_data(descriptor.clone())
+            .app_data(thresholds.clone())
+            .app_data(health_cache.clone())
+            .app_data(tile_cache.clone())
+            .app_data(access.clone())
+            .app_data(web::PathConfig::default().error_handler(path_error))
+            .app_data(web::QueryConfig::default().error_handler(query_error))
+            .app_data(web::JsonConfig::default().error_handler(json_error))
+            .wrap_fn(move |req, srv| {
+                let denied = if access_check.is_enabled() && !is_exempt(req.path()) {
+                    let qs = QString::from(req.query_string());
+                    let key = req
+                        .headers()
+                        .get("X-API-Key")
+                        .and_then(|key| key.to_str().ok())
+                        .or_else(|| qs.get("api_key"))
+                        .map(|key| key.to_string());
+                    let header = |name: &str| {
+                        let values: Vec<&str> = req
+                            .headers()
+                            .get_all(name)
+                            .filter_map(|value| value.to_str().ok())
+                            .collect();
+                        match values.is_empty() {
+                            true => None,
+                            false => Some(values.join(",")),
+                        }
+                    };
+                    let hops = forwarded_hops(
+                        header("Forwarded").as_deref(),
+                        header("X-Forwarded-For").as_deref(),
+                    );
+                    let client =
+                        access_check.client_address(req.peer_addr().map(|peer| peer.ip()), &hops);
+                    match access_check.check(key.as_deref(), &client, qs.get("feed")) {
+                        Ok(api_key) => {
+                            if let Some(api_key) = api_key {
+                                req.extensions_mut().insert(api_key.clone());
+                            }
+                            None
+                        }
+                        Err(denied) => Some(denied),
+                    }
+                } else {
+                    None
+                };
+                match denied {
+                    Some(denied) => Either::Left(ready(Ok(req
+                        .into_response(denied_response(denied))
+                        .map_into_right_body()))),
+                    None => {
+                        let response = srv.call(req);
+                        Either::Right(async move { Ok(response.await?.map_into_left_body()) })
+                    }
+                }
+            })
             .wrap(
                 DefaultHeaders::new()   
                     .add(("Server", "Kactus"))
                     .add(("Access-Control-Allow-Origin", "*"))
                     .add((
                         "Access-Control-Expose-Headers",
-                        "Server, hash, server, Hash",
+                        "Server, hash, server, Hash, ETag, Last-Modified, Cache-Control, X-Feed-Age, X-Feed-Fetched, X-Feed-Timestamp, X-Feed-Incrementality, Retry-After",
                     )),
             )
-            .wrap(middleware::Compress::default())
-            .route("/", web::get().to(index))
+            .wrap_fn(|req, srv| {
+                let started = Instant::now();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    record_request(response.request(), response.status(), started.elapsed());
+                    Ok(response)
+                }
+            })
+            //compresses on its own, see compressed
             .route("/gtfsrt/", web::get().to(gtfsrt))
             .route("/gtfsrt", web::get().to(gtfsrt))
-            .route("/gtfsrtasjson/", web::get().to(gtfsrttojson))
-            .route("/gtfsrtasjson", web::get().to(gtfsrttojson))
-            .route("/gtfsrttimes", web::get().to(gtfsrttimes))
-            .route("/gtfsrttimes/", web::get().to(gtfsrttimes))
-            .route("/gtfsrtws/", web::get().to(gtfsrtws))
-            .route("/gtfsrtws", web::get().to(gtfsrtws))
+            .service(
+                web::scope("")
+                    .wrap(middleware::Compress::default())
+                    .route("/", web::get().to(index))
+                    .route("/gtfsrtasjson/", web::get().to(gtfsrttojson))
+                    .route("/gtfsrtasjson", web::get().to(gtfsrttojson))
+                    .route("/gtfsrttimes", web::get().to(gtfsrttimes))
+                    .route("/gtfsrttimes/", web::get().to(gtfsrttimes))
+                    .route("/viewer", web::get().to(viewer))
+                    .route("/viewer/", web::get().to(viewer))
+                    .route("/tiles/vehicles/{z}/{x}/{tile}", web::get().to(vehicletile))
+                    .route("/vehicles/nearby", web::get().to(vehiclesnearby))
+                    .route("/vehicles/search", web::get().to(vehiclesearch))
+                    .route("/catalog", web::get().to(feedcatalog))
+                    .route("/catalog/", web::get().to(feedcatalog))
+                    .route("/gtfsrtws/", web::get().to(gtfsrtws))
+                    .route("/gtfsrtws", web::get().to(gtfsrtws))
+                    .route("/gtfsrtsse/", web::get().to(gtfsrtsse))
+                    .route("/gtfsrtsse", web::get().to(gtfsrtsse))
+                    .route("/admin/failedpayload", web::get().to(failedpayload))
+                    .route("/admin/usage", web::get().to(keyusage))
+                    .route("/metrics", web::get().to(metricsendpoint))
+                    .route("/health", web::get().to(health))
+                    .default_service(web::to(not_found)),
+            )
     })
-    .workers(4);
-    println!("Running on port: {}", port);
-    let _ = builder.bind(format!("127.0.0.1:{}", port)).unwrap().run().await;
+    .workers(workers);
+
+    for address in bind.iter() {
+        builder = builder.bind(address)?;
+        println!("Listening on http://{}", address);
+    }
+    if let Some(tls_config) = tls_config {
+        for address in tls_bind.iter() {
+            builder = builder.bind_rustls_0_23(address, tls_config.clone())?;
+            println!("Listening on https://{}", address);
+        }
+    }
+    if let Some(path) = unix_socket {
+        //a socket left behind by a previous run would make binding fail
+        if std::fs::metadata(&path).map_or(false, |metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(&path)?;
+        }
+        builder = builder.bind_uds(&path)?;
+        println!("Listening on unix:{}", path);
+    }
+    let _ = builder.run().await;
 
     Ok(())
 }
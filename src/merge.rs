@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use gtfs_rt::{feed_header::Incrementality, FeedHeader, FeedMessage};
+
+//merges several feeds into one message
+//entity ids are prefixed with the feed they came from, like f-bart~rt:1234
+//the header timestamp is the oldest one, so clients never think the merged data is newer than it is
+pub fn merge_feeds(messages: &[(&str, &FeedMessage)]) -> FeedMessage {
+    let mut merged = FeedMessage::default();
+    merged.header = FeedHeader {
+        gtfs_realtime_version: messages
+            .first()
+            .map(|(_, message)| message.header.gtfs_realtime_version.clone())
+            .unwrap_or_else(|| "2.0".to_string()),
+        incrementality: Some(Incrementality::FullDataset as i32),
+        timestamp: messages
+            .iter()
+            .filter_map(|(_, message)| message.header.timestamp)
+            .min(),
+    };
+
+    for (feed, message) in messages {
+        for entity in &message.entity {
+            let mut entity = entity.clone();
+            entity.id = format!("{}:{}", feed, entity.id);
+            merged.entity.push(entity);
+        }
+    }
+
+    merged
+}
+
+//puts the vehicles, trips and alerts of one feed into a single message
+//entities with the same id are combined when they carry different things, like a vehicle and its trip update
+//an exact repeat of something already there is dropped, anything else that clashes gets the category prefixed to its id
+//and a number after that if the prefixed id is taken too, so nothing is lost
+pub fn merge_categories(messages: &[(&str, &FeedMessage)]) -> FeedMessage {
+    let mut merged = FeedMessage::default();
+    merged.header = FeedHeader {
+        gtfs_realtime_version: messages
+            .first()
+            .map(|(_, message)| message.header.gtfs_realtime_version.clone())
+            .unwrap_or_else(|| "2.0".to_string()),
+        incrementality: Some(Incrementality::FullDataset as i32),
+        timestamp: messages
+            .iter()
+            .filter_map(|(_, message)| message.header.timestamp)
+            .min(),
+    };
+
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for (category, message) in messages {
+        for entity in &message.entity {
+            let existing = match positions.get(&entity.id) {
+                Some(position) => &mut merged.entity[*position],
+                None => {
+                    positions.insert(entity.id.clone(), merged.entity.len());
+                    merged.entity.push(entity.clone());
+                    continue;
+                }
+            };
+
+            if *existing == *entity {
+                continue;
+            }
+
+            let fits = (entity.vehicle.is_none() || existing.vehicle.is_none())
+                && (entity.trip_update.is_none() || existing.trip_update.is_none())
+                && entity.alert.is_none()
+                && existing.alert.is_none()
+                && entity.shape.is_none()
+                && existing.shape.is_none();
+
+            if fits {
+                if existing.vehicle.is_none() {
+                    existing.vehicle = entity.vehicle.clone();
+                }
+                if existing.trip_update.is_none() {
+                    existing.trip_update = entity.trip_update.clone();
+                }
+                continue;
+            }
+
+            //the prefixed id can be taken as well, then a number is added until it's free
+            let prefixed = format!("{}:{}", category, entity.id);
+            let mut entity = entity.clone();
+            entity.id = prefixed.clone();
+            let mut suffix = 2;
+            let mut repeat = false;
+            while let Some(position) = positions.get(&entity.id) {
+                if merged.entity[*position] == entity {
+                    repeat = true;
+                    break;
+                }
+                entity.id = format!("{}:{}", prefixed, suffix);
+                suffix += 1;
+            }
+            if !repeat {
+                positions.insert(entity.id.clone(), merged.entity.len());
+                merged.entity.push(entity);
+            }
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use gtfs_rt::{Alert, FeedEntity, VehiclePosition};
+
+    use super::*;
+
+    fn message(entities: Vec<FeedEntity>) -> FeedMessage {
+        let mut message = FeedMessage::default();
+        message.entity = entities;
+        message
+    }
+
+    fn vehicle(id: &str) -> FeedEntity {
+        let mut entity = FeedEntity::default();
+        entity.id = id.to_string();
+        entity.vehicle = Some(VehiclePosition::default());
+        entity
+    }
+
+    fn alert(id: &str, cause: i32) -> FeedEntity {
+        let mut entity = FeedEntity::default();
+        entity.id = id.to_string();
+        entity.alert = Some(Alert {
+            cause: Some(cause),
+            ..Alert::default()
+        });
+        entity
+    }
+
+    #[test]
+    fn prefixed_collisions_keep_every_entity() {
+        let vehicles = message(vec![vehicle("1"), vehicle("alerts:1")]);
+        let alerts = message(vec![alert("1", 1), alert("1", 2), alert("1", 2)]);
+        let merged = merge_categories(&[("vehicles", &vehicles), ("alerts", &alerts)]);
+        let ids: Vec<&str> = merged
+            .entity
+            .iter()
+            .map(|entity| entity.id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["1", "alerts:1", "alerts:1:2", "alerts:1:3"]);
+    }
+
+    #[test]
+    fn feeds_are_prefixed_and_keep_the_oldest_timestamp() {
+        let mut bart = message(vec![vehicle("1"), vehicle("2")]);
+        bart.header.timestamp = Some(200);
+        let mut muni = message(vec![vehicle("1")]);
+        muni.header.timestamp = Some(100);
+        let undated = message(vec![vehicle("1")]);
+        let merged = merge_feeds(&[("bart", &bart), ("muni", &muni), ("caltrain", &undated)]);
+        let ids: Vec<&str> = merged
+            .entity
+            .iter()
+            .map(|entity| entity.id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["bart:1", "bart:2", "muni:1", "caltrain:1"]);
+        assert_eq!(merged.header.timestamp, Some(100));
+        assert_eq!(
+            merged.header.incrementality,
+            Some(Incrementality::FullDataset as i32)
+        );
+    }
+}
@@ -0,0 +1,371 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{mpsc, Mutex, OnceLock},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{rt::System, web, App, HttpResponse, HttpServer};
+
+//a scraper that stalls is cut off after this
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+//seconds, from a fast redis read to a slow upstream server
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram(Vec<f64>),
+    //stores when something last happened, rendered as a gauge of the seconds since
+    Age,
+}
+
+enum Series {
+    Value(f64),
+    Histogram {
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    //keyed by the rendered label set, like feed="f-bart~rt",category="trips"
+    series: BTreeMap<String, Series>,
+}
+
+//metrics kept in memory and rendered in the prometheus text format
+//names that were never registered are ignored
+#[derive(Default)]
+pub struct Registry {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn now_seconds() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+impl Registry {
+    fn register(&self, name: &'static str, help: &'static str, kind: Kind) {
+        self.families
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_insert(Family {
+                help,
+                kind,
+                series: BTreeMap::new(),
+            });
+    }
+
+    pub fn counter(&self, name: &'static str, help: &'static str) {
+        self.register(name, help, Kind::Counter);
+    }
+
+    pub fn gauge(&self, name: &'static str, help: &'static str) {
+        self.register(name, help, Kind::Gauge);
+    }
+
+    pub fn histogram(&self, name: &'static str, help: &'static str, buckets: &[f64]) {
+        self.register(name, help, Kind::Histogram(buckets.to_vec()));
+    }
+
+    pub fn age(&self, name: &'static str, help: &'static str) {
+        self.register(name, help, Kind::Age);
+    }
+
+    pub fn inc(&self, name: &str, labels: &[(&str, &str)], by: f64) {
+        let mut families = self.families.lock().unwrap();
+        if let Some(family) = families.get_mut(name) {
+            if let Series::Value(value) = family
+                .series
+                .entry(render_labels(labels))
+                .or_insert(Series::Value(0.0))
+            {
+                *value += by;
+            }
+        }
+    }
+
+    pub fn set(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let mut families = self.families.lock().unwrap();
+        if let Some(family) = families.get_mut(name) {
+            family
+                .series
+                .insert(render_labels(labels), Series::Value(value));
+        }
+    }
+
+    //marks an age metric as having happened now
+    pub fn touch(&self, name: &str, labels: &[(&str, &str)]) {
+        self.set(name, labels, now_seconds());
+    }
+
+    pub fn observe(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let mut families = self.families.lock().unwrap();
+        if let Some(family) = families.get_mut(name) {
+            let buckets = match &family.kind {
+                Kind::Histogram(buckets) => buckets,
+                _ => return,
+            };
+            let series = family
+                .series
+                .entry(render_labels(labels))
+                .or_insert_with(|| Series::Histogram {
+                    counts: vec![0; buckets.len()],
+                    sum: 0.0,
+                    count: 0,
+                });
+            if let Series::Histogram { counts, sum, count } = series {
+                for (i, bucket) in buckets.iter().enumerate() {
+                    if value <= *bucket {
+                        counts[i] += 1;
+                    }
+                }
+                *sum += value;
+                *count += 1;
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let now = now_seconds();
+        let mut output = String::new();
+        for (name, family) in families.iter() {
+            if family.series.is_empty() {
+                continue;
+            }
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge | Kind::Age => "gauge",
+                Kind::Histogram(_) => "histogram",
+            };
+            let _ = writeln!(output, "# HELP {} {}", name, family.help);
+            let _ = writeln!(output, "# TYPE {} {}", name, kind);
+            for (labels, series) in family.series.iter() {
+                let braced = |extra: &str| {
+                    let joined = [labels.as_str(), extra]
+                        .iter()
+                        .filter(|part| !part.is_empty())
+                        .cloned()
+                        .collect::<Vec<&str>>()
+                        .join(",");
+                    if joined.is_empty() {
+                        String::new()
+                    } else {
+                        format!("{{{}}}", joined)
+                    }
+                };
+                match series {
+                    Series::Value(value) => {
+                        let value = match family.kind {
+                            Kind::Age => (now - value).max(0.0),
+                            _ => *value,
+                        };
+                        let _ = writeln!(output, "{}{} {}", name, braced(""), value);
+                    }
+                    Series::Histogram { counts, sum, count } => {
+                        if let Kind::Histogram(buckets) = &family.kind {
+                            for (bucket, bucket_count) in buckets.iter().zip(counts.iter()) {
+                                let le = format!("le=\"{}\"", bucket);
+                                let _ = writeln!(
+                                    output,
+                                    "{}_bucket{} {}",
+                                    name,
+                                    braced(&le),
+                                    bucket_count
+                                );
+                            }
+                            let _ = writeln!(
+                                output,
+                                "{}_bucket{} {}",
+                                name,
+                                braced("le=\"+Inf\""),
+                                count
+                            );
+                        }
+                        let _ = writeln!(output, "{}_sum{} {}", name, braced(""), sum);
+                        let _ = writeln!(output, "{}_count{} {}", name, braced(""), count);
+                    }
+                }
+            }
+        }
+        output
+    }
+}
+
+//the metrics of this process, shared by the server and the ingest engines
+pub fn metrics() -> &'static Registry {
+    static METRICS: OnceLock<Registry> = OnceLock::new();
+    METRICS.get_or_init(|| {
+        let registry = Registry::default();
+
+        registry.counter(
+            "kactus_http_requests_total",
+            "HTTP requests by route, feed and status code",
+        );
+        registry.histogram(
+            "kactus_http_request_duration_seconds",
+            "Time taken to answer HTTP requests",
+            &LATENCY_BUCKETS,
+        );
+        registry.counter(
+            "kactus_snapshot_cache_total",
+            "Snapshot lookups answered from memory (hit) or read from redis (miss)",
+        );
+
+        registry.counter(
+            "kactus_fetches_total",
+            "Upstream fetches by outcome: ok, undecodable, status or network",
+        );
+        registry.histogram(
+            "kactus_fetch_duration_seconds",
+            "Time taken by upstream fetches that got a response",
+            &LATENCY_BUCKETS,
+        );
+        registry.counter(
+            "kactus_fetch_bytes_total",
+            "Bytes received from upstream feeds",
+        );
+        registry.age(
+            "kactus_feed_age_seconds",
+            "Seconds since a snapshot of the feed was last inserted",
+        );
+        registry.histogram(
+            "kactus_ingest_loop_duration_seconds",
+            "Time taken by one pass of the ingest loop, in ingestv4 every feed has its own loop",
+            &LATENCY_BUCKETS,
+        );
+
+        registry
+    })
+}
+
+async fn scrape() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "text/plain; version=0.0.4"))
+        .body(metrics().render())
+}
+
+fn routes(config: &mut web::ServiceConfig) {
+    config.route("/metrics", web::get().to(scrape));
+}
+
+//serves /metrics on its own port, for processes that aren't web servers
+//there's no auth, so bind it to localhost or an address only the scraper can reach
+//runs its own actix system on a thread, so it works next to any runtime the process has
+pub fn serve(address: &str, port: u16) -> std::io::Result<()> {
+    let address = address.to_string();
+    let (bound, result) = mpsc::channel();
+    thread::spawn(move || {
+        let server = HttpServer::new(|| App::new().configure(routes))
+            .workers(1)
+            .client_request_timeout(SCRAPE_TIMEOUT)
+            .client_disconnect_timeout(SCRAPE_TIMEOUT)
+            .bind((address.as_str(), port));
+        match server {
+            Ok(server) => {
+                let _ = bound.send(Ok(()));
+                if let Err(e) = System::new().block_on(server.run()) {
+                    println!("Metrics listener stopped: {}", e);
+                }
+            }
+            Err(e) => {
+                let _ = bound.send(Err(e));
+            }
+        }
+    });
+    result
+        .recv()
+        .unwrap_or_else(|_| Err(std::io::Error::other("metrics listener thread exited")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, read_body, TestRequest},
+    };
+
+    #[test]
+    fn renders_help_and_type_once_per_family() {
+        let registry = Registry::default();
+        registry.counter("requests_total", "Requests by feed");
+        registry.histogram("duration_seconds", "Time taken", &[0.1, 1.0]);
+        registry.gauge("unused", "Never set, so left out");
+        registry.inc("requests_total", &[("feed", "a")], 1.0);
+        registry.inc("requests_total", &[("feed", "b")], 2.0);
+        registry.observe("duration_seconds", &[], 0.5);
+
+        let rendered = registry.render();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "# HELP duration_seconds Time taken",
+                "# TYPE duration_seconds histogram",
+                "duration_seconds_bucket{le=\"0.1\"} 0",
+                "duration_seconds_bucket{le=\"1\"} 1",
+                "duration_seconds_bucket{le=\"+Inf\"} 1",
+                "duration_seconds_sum 0.5",
+                "duration_seconds_count 1",
+                "# HELP requests_total Requests by feed",
+                "# TYPE requests_total counter",
+                "requests_total{feed=\"a\"} 1",
+                "requests_total{feed=\"b\"} 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        let registry = Registry::default();
+        registry.counter("requests_total", "Requests by feed");
+        registry.inc("requests_total", &[("feed", "a\"b\\c\nd")], 1.0);
+        assert!(registry
+            .render()
+            .contains(r#"requests_total{feed="a\"b\\c\nd"} 1"#));
+    }
+
+    #[actix_web::test]
+    async fn only_answers_get_metrics() {
+        metrics().inc("kactus_fetches_total", &[("outcome", "ok")], 1.0);
+        let app = init_service(App::new().configure(routes)).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_body(response).await;
+        assert!(String::from_utf8_lossy(&body).contains("# TYPE kactus_fetches_total counter"));
+
+        let response = call_service(&app, TestRequest::get().uri("/other").to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = call_service(&app, TestRequest::post().uri("/metrics").to_request()).await;
+        assert!(response.status().is_client_error());
+    }
+}
@@ -0,0 +1,172 @@
+#[macro_use]
+extern crate serde_derive;
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use kactus::health::{check_all, FeedHealth, HealthState, HealthThresholds};
+
+//what the monitor last told the webhooks about a feed, and what it has seen since
+struct Tracked {
+    reported: HealthState,
+    //a different state seen on the latest checks, and how many checks in a row it lasted
+    pending: Option<(HealthState, u32)>,
+}
+
+#[derive(Serialize)]
+struct WebhookEvent<'a> {
+    //stale when a feed stops being healthy, recovered when it's healthy again, changed otherwise
+    event: &'a str,
+    feed: &'a str,
+    previous: HealthState,
+    state: HealthState,
+    age: Option<f64>,
+    reason: &'a str,
+    time: u64,
+}
+
+fn event_name(previous: HealthState, state: HealthState) -> &'static str {
+    match (previous, state) {
+        (HealthState::Healthy, _) => "stale",
+        (_, HealthState::Healthy) => "recovered",
+        _ => "changed",
+    }
+}
+
+async fn notify(client: &reqwest::Client, webhooks: &[String], event: &WebhookEvent<'_>) {
+    println!(
+        "{} {}: {:?} -> {:?} {}",
+        event.event, event.feed, event.previous, event.state, event.reason
+    );
+    for webhook in webhooks {
+        let result = client
+            .post(webhook)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(event).unwrap())
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await;
+        match result {
+            Ok(response) if !response.status().is_success() => {
+                println!("webhook {} returned {}", webhook, response.status());
+            }
+            Err(e) => println!("webhook {} failed: {}", webhook, e),
+            Ok(_) => {}
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> color_eyre::eyre::Result<()> {
+    color_eyre::install()?;
+
+    let arguments = std::env::args();
+    let arguments = arguments::parse(arguments).unwrap();
+
+    //one webhook url per line
+    let webhooks: Vec<String> = match arguments.get::<String>("webhooks") {
+        Some(filename) => BufReader::new(File::open(filename)?)
+            .lines()
+            .collect::<Result<Vec<String>, _>>()?
+            .into_iter()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let thresholds = match arguments.get::<String>("thresholds") {
+        Some(filename) => HealthThresholds::load(&filename).unwrap(),
+        None => HealthThresholds::default(),
+    };
+
+    let interval = match arguments.get::<u64>("interval") {
+        Some(interval) => interval,
+        None => 30,
+    };
+
+    //checks in a row a new state has to last before webhooks hear about it
+    let debounce = match arguments.get::<u32>("debounce") {
+        Some(debounce) => debounce.max(1),
+        None => 3,
+    };
+
+    if webhooks.is_empty() {
+        println!("No webhooks given, transitions will only be printed");
+    }
+
+    let client = reqwest::Client::new();
+    let redisclient = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut tracked: HashMap<String, Tracked> = HashMap::new();
+
+    loop {
+        let now_millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let healths: Vec<FeedHealth> = match redisclient
+            .get_connection()
+            .and_then(|mut con| check_all(&mut con, &thresholds, now_millis))
+        {
+            Ok(healths) => healths,
+            Err(e) => {
+                println!("Error reading feeds from redis: {}", e);
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+                continue;
+            }
+        };
+
+        for health in healths.iter() {
+            let entry = match tracked.get_mut(&health.feed) {
+                Some(entry) => entry,
+                //the first state seen is taken as is, so a restart doesn't fire for every broken feed
+                None => {
+                    tracked.insert(
+                        health.feed.clone(),
+                        Tracked {
+                            reported: health.state,
+                            pending: None,
+                        },
+                    );
+                    continue;
+                }
+            };
+
+            if health.state == entry.reported {
+                entry.pending = None;
+                continue;
+            }
+
+            let seen = match entry.pending {
+                Some((state, seen)) if state == health.state => seen + 1,
+                _ => 1,
+            };
+            if seen < debounce {
+                entry.pending = Some((health.state, seen));
+                continue;
+            }
+
+            let event = WebhookEvent {
+                event: event_name(entry.reported, health.state),
+                feed: &health.feed,
+                previous: entry.reported,
+                state: health.state,
+                age: health.age,
+                reason: &health.reason,
+                time: now_millis,
+            };
+            notify(&client, &webhooks, &event).await;
+            entry.reported = health.state;
+            entry.pending = None;
+        }
+
+        tracked.retain(|feed, _| healths.iter().any(|health| health.feed == *feed));
+
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
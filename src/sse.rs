@@ -0,0 +1,208 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use actix_web::web::Bytes;
+use base64::Engine;
+use futures::Stream;
+use qstring::QString;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::cache::Snapshot;
+
+//comment line sent when nothing happened for a while, so proxies don't close the stream
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+//what each update event carries, chosen with data=
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventData {
+    //time, hash and size only, clients fetch /gtfsrt themselves
+    Notification,
+    //the snapshot as base64 protobuf
+    Protobuf,
+    //the snapshot as json, like /gtfsrtasjson
+    Json,
+}
+
+impl EventData {
+    pub fn from_query(qs: &QString) -> Result<EventData, String> {
+        match qs.get("data") {
+            None | Some("notification") => Ok(EventData::Notification),
+            Some("protobuf") | Some("pb") => Ok(EventData::Protobuf),
+            Some("json") => Ok(EventData::Json),
+            Some(other) => Err(format!("unknown data {}", other)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    feed: &'a str,
+    category: &'a str,
+    time: u64,
+    hash: String,
+    size: usize,
+}
+
+//one update event, its id is the snapshot time so Last-Event-ID says what the client already has
+fn event(feed: &str, category: &str, snapshot: &Snapshot, data: EventData) -> Bytes {
+    let payload = match data {
+        EventData::Notification => serde_json::to_string(&Notification {
+            feed,
+            category,
+            time: snapshot.time,
+            hash: snapshot.hash_string(),
+            size: snapshot.bytes.len(),
+        })
+        .unwrap(),
+        EventData::Protobuf => base64::engine::general_purpose::STANDARD.encode(&snapshot.bytes),
+        //the error event carries the same json as an upstream_invalid http response
+        EventData::Json => match snapshot.message(feed, category) {
+            Ok(_) => snapshot.json().unwrap().to_string(),
+            Err(e) => {
+                return Bytes::from(format!(
+                    "id: {}\nevent: error\ndata: {}\n\n",
+                    snapshot.time,
+                    e.body()
+                ))
+            }
+        },
+    };
+    Bytes::from(format!(
+        "id: {}\nevent: update\ndata: {}\n\n",
+        snapshot.time, payload
+    ))
+}
+
+struct EventState {
+    feed: String,
+    category: String,
+    data: EventData,
+    receiver: broadcast::Receiver<Arc<Snapshot>>,
+    //sent before waiting for updates, None when the client already has the current snapshot
+    pending: Option<Arc<Snapshot>>,
+    //time of the newest snapshot the client has
+    last_time: u64,
+}
+
+//the body of a text/event-stream response following one feed and category
+pub fn event_stream(
+    feed: String,
+    category: String,
+    data: EventData,
+    receiver: broadcast::Receiver<Arc<Snapshot>>,
+    current: Arc<Snapshot>,
+    last_event_id: Option<u64>,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    let last_time = last_event_id.unwrap_or(0);
+    let state = EventState {
+        feed,
+        category,
+        data,
+        receiver,
+        pending: if current.time > last_time {
+            Some(current)
+        } else {
+            None
+        },
+        last_time,
+    };
+
+    futures::stream::unfold(state, |mut state| async move {
+        if let Some(snapshot) = state.pending.take() {
+            state.last_time = snapshot.time;
+            let event = event(&state.feed, &state.category, &snapshot, state.data);
+            return Some((Ok(event), state));
+        }
+        loop {
+            tokio::select! {
+                received = state.receiver.recv() => match received {
+                    Ok(snapshot) if snapshot.time > state.last_time => {
+                        state.last_time = snapshot.time;
+                        let event = event(&state.feed, &state.category, &snapshot, state.data);
+                        return Some((Ok(event), state));
+                    }
+                    Ok(_) => continue,
+                    //fell behind, carry on from the newest snapshot
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = tokio::time::sleep(KEEP_ALIVE_INTERVAL) => {
+                    return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), state));
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use gtfs_rt::FeedMessage;
+    use prost::Message;
+
+    use super::*;
+
+    fn snapshot(time: u64) -> Arc<Snapshot> {
+        Arc::new(Snapshot::new(time, FeedMessage::default().encode_to_vec()))
+    }
+
+    fn event_id(event: Bytes) -> String {
+        let event = String::from_utf8(event.to_vec()).unwrap();
+        event.lines().next().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn starts_with_the_current_snapshot_without_last_event_id() {
+        let (_sender, receiver) = broadcast::channel(4);
+        let events = event_stream(
+            String::from("f"),
+            String::from("vehicles"),
+            EventData::Notification,
+            receiver,
+            snapshot(2000),
+            None,
+        );
+        futures::pin_mut!(events);
+        let first = events.next().await.unwrap().unwrap();
+        assert_eq!(event_id(first.clone()), "id: 2000");
+        let text = String::from_utf8(first.to_vec()).unwrap();
+        assert!(text.contains("event: update"));
+        assert!(text.contains(&format!("\"hash\":\"{}\"", snapshot(2000).hash_string())));
+    }
+
+    #[actix_web::test]
+    async fn resumes_after_last_event_id() {
+        let (sender, receiver) = broadcast::channel(4);
+        let events = event_stream(
+            String::from("f"),
+            String::from("vehicles"),
+            EventData::Notification,
+            receiver,
+            snapshot(2000),
+            Some(2000),
+        );
+        futures::pin_mut!(events);
+        //the client already has 2000, and anything older than it is never sent
+        for time in [1500, 2000, 3000, 2500, 4000] {
+            assert!(sender.send(snapshot(time)).is_ok());
+        }
+        assert_eq!(event_id(events.next().await.unwrap().unwrap()), "id: 3000");
+        assert_eq!(event_id(events.next().await.unwrap().unwrap()), "id: 4000");
+        drop(sender);
+        assert!(events.next().await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn an_older_last_event_id_gets_the_current_snapshot() {
+        let (_sender, receiver) = broadcast::channel(4);
+        let events = event_stream(
+            String::from("f"),
+            String::from("vehicles"),
+            EventData::Protobuf,
+            receiver,
+            snapshot(2000),
+            Some(1000),
+        );
+        futures::pin_mut!(events);
+        assert_eq!(event_id(events.next().await.unwrap().unwrap()), "id: 2000");
+    }
+}
@@ -0,0 +1,452 @@
+use std::{
+    collections::{HashMap, HashSet},
+    f64::consts::PI,
+    hash::Hasher,
+    sync::{Arc, Mutex},
+};
+
+use prost::Message;
+use rstar::AABB;
+
+use crate::vehicles::FoundVehicle;
+
+//coordinates inside a tile go from 0 to this
+pub const EXTENT: u32 = 4096;
+//points this far outside a tile are still drawn in it, so icons on the edge aren't cut in half
+const BUFFER: f64 = 64.0;
+pub const MAX_ZOOM: u32 = 22;
+//web mercator can't show the poles
+const MAX_LATITUDE: f64 = 85.051_128_78;
+pub const VEHICLES_LAYER: &str = "vehicles";
+//tiles below this zoom cover so much of the world that every request for them encodes most vehicles,
+//so they're kept for as long as clients may cache them instead
+pub const CACHED_BELOW_ZOOM: u32 = 8;
+const TILE_CACHE_MILLIS: u64 = 5000;
+
+//the parts of the mapbox vector tile spec, vector_tile.proto version 2, that points need
+#[derive(Clone, PartialEq, Message)]
+struct Tile {
+    #[prost(message, repeated, tag = "3")]
+    layers: Vec<Layer>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Layer {
+    #[prost(uint32, required, tag = "15")]
+    version: u32,
+    #[prost(string, required, tag = "1")]
+    name: String,
+    #[prost(message, repeated, tag = "2")]
+    features: Vec<Feature>,
+    #[prost(string, repeated, tag = "3")]
+    keys: Vec<String>,
+    #[prost(message, repeated, tag = "4")]
+    values: Vec<Value>,
+    #[prost(uint32, optional, tag = "5")]
+    extent: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Feature {
+    #[prost(uint64, optional, tag = "1")]
+    id: Option<u64>,
+    //pairs of key and value indexes into the layer
+    #[prost(uint32, repeated, packed = "true", tag = "2")]
+    tags: Vec<u32>,
+    //GeomType, 1 is a point
+    #[prost(uint32, optional, tag = "3")]
+    r#type: Option<u32>,
+    #[prost(uint32, repeated, packed = "true", tag = "4")]
+    geometry: Vec<u32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Value {
+    #[prost(string, optional, tag = "1")]
+    string_value: Option<String>,
+    #[prost(double, optional, tag = "3")]
+    double_value: Option<f64>,
+}
+
+const POINT: u32 = 1;
+const MOVE_TO_ONE: u32 = 1 | (1 << 3);
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+//a tile in the usual z/x/y scheme, y counting down from the north
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileId {
+    pub z: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl TileId {
+    pub fn new(z: u32, x: u32, y: u32) -> Option<TileId> {
+        if z > MAX_ZOOM || x >= 1 << z || y >= 1 << z {
+            return None;
+        }
+        Some(TileId { z, x, y })
+    }
+
+    fn tiles(&self) -> f64 {
+        (1u32 << self.z) as f64
+    }
+
+    //where a point falls in tile units, the world being tiles() wide
+    fn project(&self, lat: f64, lon: f64) -> (f64, f64) {
+        let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+        let x = (lon + 180.0) / 360.0 * self.tiles();
+        let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * self.tiles();
+        (x, y)
+    }
+
+    fn unproject(&self, x: f64, y: f64) -> (f64, f64) {
+        let lon = x / self.tiles() * 360.0 - 180.0;
+        let lat = (PI * (1.0 - 2.0 * y / self.tiles())).sinh().atan().to_degrees();
+        (lat, lon)
+    }
+
+    //the [lon, lat] rectangle covered by the tile and its buffer
+    pub fn envelope(&self) -> AABB<[f64; 2]> {
+        let buffer = BUFFER / EXTENT as f64;
+        let (north, west) = self.unproject(self.x as f64 - buffer, self.y as f64 - buffer);
+        let (south, east) = self.unproject(self.x as f64 + 1.0 + buffer, self.y as f64 + 1.0 + buffer);
+        AABB::from_corners([west, south], [east, north])
+    }
+
+    //position inside the tile in tile coordinates
+    fn position(&self, lat: f64, lon: f64) -> (i32, i32) {
+        let (x, y) = self.project(lat, lon);
+        (
+            ((x - self.x as f64) * EXTENT as f64).round() as i32,
+            ((y - self.y as f64) * EXTENT as f64).round() as i32,
+        )
+    }
+}
+
+enum Property<'a> {
+    Text(&'a str),
+    Number(f64),
+}
+
+//keys and values are shared by all features of a layer and referenced by index
+#[derive(Default)]
+struct LayerBuilder {
+    keys: Vec<String>,
+    key_indexes: HashMap<String, u32>,
+    values: Vec<Value>,
+    text_indexes: HashMap<String, u32>,
+    number_indexes: HashMap<u64, u32>,
+    features: Vec<Feature>,
+}
+
+impl LayerBuilder {
+    fn key(&mut self, key: &str) -> u32 {
+        if let Some(index) = self.key_indexes.get(key) {
+            return *index;
+        }
+        let index = self.keys.len() as u32;
+        self.keys.push(key.to_string());
+        self.key_indexes.insert(key.to_string(), index);
+        index
+    }
+
+    fn value(&mut self, property: &Property) -> u32 {
+        let (existing, value) = match property {
+            Property::Text(text) => (
+                self.text_indexes.get(*text).copied(),
+                Value {
+                    string_value: Some(text.to_string()),
+                    ..Value::default()
+                },
+            ),
+            Property::Number(number) => (
+                self.number_indexes.get(&number.to_bits()).copied(),
+                Value {
+                    double_value: Some(*number),
+                    ..Value::default()
+                },
+            ),
+        };
+        if let Some(index) = existing {
+            return index;
+        }
+        let index = self.values.len() as u32;
+        self.values.push(value);
+        match property {
+            Property::Text(text) => self.text_indexes.insert(text.to_string(), index),
+            Property::Number(number) => self.number_indexes.insert(number.to_bits(), index),
+        };
+        index
+    }
+
+    fn point(&mut self, id: u64, (x, y): (i32, i32), properties: &[(&str, Option<Property>)]) {
+        let mut tags = Vec::with_capacity(properties.len() * 2);
+        for (key, property) in properties {
+            if let Some(property) = property {
+                tags.push(self.key(key));
+                tags.push(self.value(property));
+            }
+        }
+        self.features.push(Feature {
+            id: Some(id),
+            tags,
+            r#type: Some(POINT),
+            geometry: vec![MOVE_TO_ONE, zigzag(x), zigzag(y)],
+        });
+    }
+
+    fn build(self, name: &str) -> Layer {
+        Layer {
+            version: 2,
+            name: name.to_string(),
+            features: self.features,
+            keys: self.keys,
+            values: self.values,
+            extent: Some(EXTENT),
+        }
+    }
+}
+
+//the same vehicle gets the same id in every tile and at every zoom, so clients can follow it
+fn feature_id(feed: &str, entity_id: &str) -> u64 {
+    let mut hasher = metrohash::MetroHash64::new();
+    hasher.write(feed.as_bytes());
+    hasher.write(&[0]);
+    hasher.write(entity_id.as_bytes());
+    hasher.finish()
+}
+
+//an encoded tile with a point for every vehicle, tagged with its feed, route, trip, vehicle id, label and bearing
+pub fn vehicle_tile(tile: TileId, vehicles: &[FoundVehicle]) -> Vec<u8> {
+    let mut layer = LayerBuilder::default();
+    for found in vehicles {
+        let position = match found.vehicle.position.as_ref() {
+            Some(position) => position,
+            None => continue,
+        };
+        let trip = found.vehicle.trip.as_ref();
+        let descriptor = found.vehicle.vehicle.as_ref();
+        layer.point(
+            feature_id(found.feed, found.entity_id),
+            tile.position(position.latitude as f64, position.longitude as f64),
+            &[
+                ("feed", Some(Property::Text(found.feed))),
+                (
+                    "route_id",
+                    trip.and_then(|trip| trip.route_id.as_deref()).map(Property::Text),
+                ),
+                (
+                    "trip_id",
+                    trip.and_then(|trip| trip.trip_id.as_deref()).map(Property::Text),
+                ),
+                (
+                    "vehicle_id",
+                    Some(Property::Text(
+                        descriptor.and_then(|d| d.id.as_deref()).unwrap_or(found.entity_id),
+                    )),
+                ),
+                (
+                    "label",
+                    descriptor.and_then(|d| d.label.as_deref()).map(Property::Text),
+                ),
+                (
+                    "bearing",
+                    position.bearing.map(|bearing| Property::Number(bearing as f64)),
+                ),
+            ],
+        );
+    }
+    Tile {
+        layers: vec![layer.build(VEHICLES_LAYER)],
+    }
+    .encode_to_vec()
+}
+
+//encoded tiles by tile and the sorted feeds hidden from the consumer, with the time they were rendered
+type RenderedTiles = HashMap<(TileId, Vec<String>), (u64, Arc<Vec<u8>>)>;
+
+//low zoom tiles, kept for as long as clients may cache them
+#[derive(Default)]
+pub struct TileCache {
+    tiles: Mutex<RenderedTiles>,
+}
+
+impl TileCache {
+    //the cached tile when it's recent enough, otherwise render is called without holding the lock
+    pub fn get_or_render(
+        &self,
+        tile: TileId,
+        hidden: &HashSet<String>,
+        now_millis: u64,
+        render: impl FnOnce() -> Vec<u8>,
+    ) -> Arc<Vec<u8>> {
+        if tile.z >= CACHED_BELOW_ZOOM {
+            return Arc::new(render());
+        }
+        let mut hidden: Vec<String> = hidden.iter().cloned().collect();
+        hidden.sort_unstable();
+        let key = (tile, hidden);
+        if let Some((rendered, encoded)) = self.tiles.lock().unwrap().get(&key) {
+            if now_millis.saturating_sub(*rendered) < TILE_CACHE_MILLIS {
+                return encoded.clone();
+            }
+        }
+
+        let encoded = Arc::new(render());
+        let mut tiles = self.tiles.lock().unwrap();
+        tiles.retain(|_, (rendered, _)| now_millis.saturating_sub(*rendered) < TILE_CACHE_MILLIS);
+        tiles.insert(key, (now_millis, encoded.clone()));
+        encoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gtfs_rt::{Position, TripDescriptor, VehiclePosition};
+    use rstar::Envelope;
+
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    fn vehicle(lat: f32, lon: f32, route_id: &str, bearing: Option<f32>) -> VehiclePosition {
+        VehiclePosition {
+            position: Some(Position {
+                latitude: lat,
+                longitude: lon,
+                bearing,
+                ..Position::default()
+            }),
+            trip: Some(TripDescriptor {
+                route_id: Some(route_id.to_string()),
+                ..TripDescriptor::default()
+            }),
+            ..VehiclePosition::default()
+        }
+    }
+
+    #[test]
+    fn rejects_tiles_outside_the_zoom() {
+        assert!(TileId::new(0, 0, 0).is_some());
+        assert!(TileId::new(1, 2, 0).is_none());
+        assert!(TileId::new(1, 0, 2).is_none());
+        assert!(TileId::new(MAX_ZOOM + 1, 0, 0).is_none());
+    }
+
+    #[test]
+    fn projects_into_web_mercator() {
+        let world = TileId::new(0, 0, 0).unwrap();
+        let (x, y) = world.project(0.0, 0.0);
+        assert!(close(x, 0.5) && close(y, 0.5));
+        let (x, y) = world.project(MAX_LATITUDE, -180.0);
+        assert!(close(x, 0.0) && close(y, 0.0));
+        //the poles are clamped to the edge of the map
+        let (_, y) = world.project(90.0, 0.0);
+        assert!(close(y, 0.0));
+
+        //san francisco is in 12/655/1583
+        let tile = TileId::new(12, 655, 1583).unwrap();
+        let (x, y) = tile.project(37.7749, -122.4194);
+        assert_eq!((x as u32, y as u32), (655, 1583));
+        let (lat, lon) = tile.unproject(x, y);
+        assert!(close(lat, 37.7749) && close(lon, -122.4194));
+    }
+
+    #[test]
+    fn envelope_covers_the_tile_and_its_buffer() {
+        let world = TileId::new(0, 0, 0).unwrap();
+        let envelope = world.envelope();
+        let (lower, upper) = (envelope.lower(), envelope.upper());
+        assert!(lower[0] < -180.0 && upper[0] > 180.0);
+        assert!(lower[1] < -MAX_LATITUDE && upper[1] > MAX_LATITUDE);
+
+        //the north east quarter of the world
+        let tile = TileId::new(1, 1, 0).unwrap();
+        let envelope = tile.envelope();
+        assert!(envelope.contains_point(&[1.0, 1.0]));
+        assert!(envelope.contains_point(&[179.0, 80.0]));
+        assert!(!envelope.contains_point(&[-10.0, 1.0]));
+        assert!(!envelope.contains_point(&[10.0, -10.0]));
+    }
+
+    #[test]
+    fn positions_are_in_tile_units() {
+        let tile = TileId::new(1, 1, 1).unwrap();
+        assert_eq!(tile.position(0.0, 0.0), (0, 0));
+        //points in the buffer go slightly outside 0..EXTENT
+        let (x, _) = tile.position(-0.5, -0.5);
+        assert!(x < 0 && x > -(BUFFER as i32));
+    }
+
+    #[test]
+    fn zigzag_encodes_signs() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(-2), 3);
+        assert_eq!(zigzag(2048), 4096);
+    }
+
+    #[test]
+    fn encodes_points_with_shared_keys_and_values() {
+        let tile = TileId::new(1, 1, 1).unwrap();
+        let first = vehicle(0.0, 0.0, "red", Some(90.0));
+        let second = vehicle(0.0, 0.0, "red", None);
+        let vehicles = [
+            FoundVehicle {
+                feed: "f",
+                entity_id: "1",
+                vehicle: &first,
+            },
+            FoundVehicle {
+                feed: "f",
+                entity_id: "2",
+                vehicle: &second,
+            },
+        ];
+        let decoded = Tile::decode(vehicle_tile(tile, &vehicles).as_slice()).unwrap();
+        assert_eq!(decoded.layers.len(), 1);
+        let layer = &decoded.layers[0];
+        assert_eq!(layer.name, VEHICLES_LAYER);
+        assert_eq!(layer.extent, Some(EXTENT));
+        let keys = ["feed", "route_id", "vehicle_id", "bearing"];
+        assert_eq!(layer.keys, keys);
+        //"f" and "red" are stored once, each vehicle id and the bearing once more
+        assert_eq!(layer.values.len(), 5);
+
+        let feature = &layer.features[0];
+        assert_eq!(feature.r#type, Some(POINT));
+        //one MoveTo with a single point at the top left corner
+        assert_eq!(feature.geometry, vec![9, 0, 0]);
+        assert_eq!(feature.tags, vec![0, 0, 1, 1, 2, 2, 3, 3]);
+        assert_eq!(feature.id, Some(feature_id("f", "1")));
+        assert_eq!(layer.features[1].tags, vec![0, 0, 1, 1, 2, 4]);
+        assert_ne!(feature_id("f", "1"), feature_id("f1", ""));
+    }
+
+    #[test]
+    fn caches_low_zoom_tiles_per_hidden_set() {
+        let cache = TileCache::default();
+        //the byte a tile was rendered with, so a cached tile shows up as an older byte
+        let render = |tile: TileId, hidden: &HashSet<String>, now: u64, byte: u8| {
+            cache.get_or_render(tile, hidden, now, || vec![byte])[0]
+        };
+        let world = TileId::new(0, 0, 0).unwrap();
+        let none = HashSet::new();
+        let hidden = HashSet::from([String::from("f")]);
+        assert_eq!(render(world, &none, 0, 1), 1);
+        assert_eq!(render(world, &none, 1000, 2), 1);
+        assert_eq!(render(world, &hidden, 1000, 3), 3);
+        assert_eq!(render(world, &none, TILE_CACHE_MILLIS, 4), 4);
+
+        let street = TileId::new(CACHED_BELOW_ZOOM, 0, 0).unwrap();
+        assert_eq!(render(street, &none, 0, 5), 5);
+        assert_eq!(render(street, &none, 0, 6), 6);
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    fs,
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    crypto::ring::{default_provider, sign::any_supported_type},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+
+//how often the certificate files are checked for a renewal
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> anyhow::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates in {}", cert_path);
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)?;
+    Ok(CertifiedKey::new(certs, any_supported_type(&key)?))
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+//hands out whichever certificate was loaded last, so renewals apply without a restart
+#[derive(Debug)]
+pub struct ReloadingCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl ReloadingCert {
+    //loads the certificate and key, then reloads them whenever either file changes
+    //a broken renewal is logged and the previous certificate stays in use
+    pub fn watch(cert_path: &str, key_path: &str) -> anyhow::Result<Arc<ReloadingCert>> {
+        let resolver = Arc::new(ReloadingCert {
+            current: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+        });
+
+        let watched = resolver.clone();
+        let cert_path = cert_path.to_string();
+        let key_path = key_path.to_string();
+        thread::spawn(move || {
+            let mut last = (modified(&cert_path), modified(&key_path));
+            loop {
+                thread::sleep(RELOAD_INTERVAL);
+                let now = (modified(&cert_path), modified(&key_path));
+                if now == last {
+                    continue;
+                }
+                match load_certified_key(&cert_path, &key_path) {
+                    Ok(certified_key) => {
+                        *watched.current.write().unwrap() = Arc::new(certified_key);
+                        println!("Reloaded TLS certificate from {}", cert_path);
+                        last = now;
+                    }
+                    //certbot writes the two files one after the other, so try again next time
+                    Err(e) => println!("Could not reload TLS certificate: {}", e),
+                }
+            }
+        });
+
+        Ok(resolver)
+    }
+}
+
+pub fn server_config(cert_path: &str, key_path: &str) -> anyhow::Result<ServerConfig> {
+    Ok(ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(ReloadingCert::watch(cert_path, key_path)?))
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use redis::{Connection, RedisResult};
+use tokio::sync::broadcast;
+
+use crate::{
+    cache::{Snapshot, SnapshotCache},
+    catalog::{self, CatalogQuery},
+    health::HealthThresholds,
+    vehicles::{AllVehicles, VEHICLES_CATEGORY},
+    FeedUpdateNotification, FEED_UPDATE_PATTERN,
+};
+
+//how often gtfsrttime is checked for feeds someone is subscribed to, on top of the pub/sub notifications
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+//only the newest snapshot is worth sending, so a subscriber that falls behind skips straight to it
+const CHANNEL_CAPACITY: usize = 1;
+
+//new snapshots of a feed and category, pushed to everyone subscribed to it
+#[derive(Default)]
+pub struct FeedUpdates {
+    channels: Mutex<HashMap<String, broadcast::Sender<Arc<Snapshot>>>>,
+}
+
+impl FeedUpdates {
+    pub fn new() -> FeedUpdates {
+        FeedUpdates::default()
+    }
+
+    pub fn subscribe(&self, feed: &str, category: &str) -> broadcast::Receiver<Arc<Snapshot>> {
+        let key = format!("{}|{}", feed, category);
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    pub fn publish(&self, feed: &str, category: &str, snapshot: Arc<Snapshot>) {
+        let key = format!("{}|{}", feed, category);
+        if let Some(sender) = self.channels.lock().unwrap().get(&key) {
+            let _ = sender.send(snapshot);
+        }
+    }
+
+    pub fn is_watched(&self, feed: &str, category: &str) -> bool {
+        let key = format!("{}|{}", feed, category);
+        self.channels
+            .lock()
+            .unwrap()
+            .get(&key)
+            .map_or(false, |sender| sender.receiver_count() > 0)
+    }
+
+    //feeds and categories that still have subscribers, forgetting the ones that don't
+    pub fn watched(&self) -> Vec<(String, String)> {
+        let mut channels = self.channels.lock().unwrap();
+        channels.retain(|_, sender| sender.receiver_count() > 0);
+        channels
+            .keys()
+            .filter_map(|key| key.split_once('|'))
+            .map(|(feed, category)| (feed.to_string(), category.to_string()))
+            .collect()
+    }
+}
+
+//publishes every new snapshot of the feeds that have subscribers, and keeps every vehicles feed in all_vehicles
+//ingesters announce new snapshots on redis pub/sub, gtfsrttime is still polled now and then
+//in case a notification was missed while the connection was down
+//runs on its own thread since the redis connections are blocking
+pub fn watch(updates: Arc<FeedUpdates>, cache: Arc<SnapshotCache>, all_vehicles: Arc<AllVehicles>) {
+    thread::spawn(move || {
+        let redisclient = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let mut watcher = Watcher {
+            updates,
+            cache,
+            all_vehicles,
+            last_times: HashMap::new(),
+        };
+        loop {
+            if let Err(e) = watcher.run(&redisclient) {
+                println!("Feed update watcher lost redis: {}", e);
+            }
+            thread::sleep(RECONNECT_INTERVAL);
+        }
+    });
+}
+
+struct Watcher {
+    updates: Arc<FeedUpdates>,
+    cache: Arc<SnapshotCache>,
+    all_vehicles: Arc<AllVehicles>,
+    //time of the last snapshot published for each feed and category
+    last_times: HashMap<String, u64>,
+}
+
+impl Watcher {
+    fn run(&mut self, redisclient: &redis::Client) -> RedisResult<()> {
+        let mut con = redisclient.get_connection()?;
+        let mut pubsub_con = redisclient.get_connection()?;
+        let mut pubsub = pubsub_con.as_pubsub();
+        pubsub.psubscribe(FEED_UPDATE_PATTERN)?;
+        pubsub.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        //catch up on anything that changed while disconnected, including vehicles feeds not seen yet
+        let query = CatalogQuery {
+            category: Some(String::from(VEHICLES_CATEGORY)),
+            limit: usize::MAX,
+            ..CatalogQuery::default()
+        };
+        for status in catalog::list(&mut con, &query, &HealthThresholds::default(), 0)?.feeds {
+            self.check(&mut con, &status.feed, VEHICLES_CATEGORY)?;
+        }
+        self.poll(&mut con)?;
+        let mut last_poll = Instant::now();
+        loop {
+            match pubsub.get_message() {
+                Ok(msg) => {
+                    if let Ok(notification) = serde_json::from_str::<FeedUpdateNotification>(
+                        &msg.get_payload::<String>()?,
+                    ) {
+                        self.check(&mut con, &notification.feed, &notification.category)?;
+                    }
+                }
+                Err(e) if e.is_timeout() => {}
+                Err(e) => return Err(e),
+            }
+            if last_poll.elapsed() >= POLL_INTERVAL {
+                self.poll(&mut con)?;
+                last_poll = Instant::now();
+            }
+        }
+    }
+
+    //looks at every watched feed and every vehicles feed, forgetting the ones nobody watches anymore
+    fn poll(&mut self, con: &mut Connection) -> RedisResult<()> {
+        let watched = self.updates.watched();
+        self.last_times.retain(|key, _| {
+            watched
+                .iter()
+                .any(|(feed, category)| *key == format!("{}|{}", feed, category))
+        });
+        for feed in self.all_vehicles.feeds() {
+            if !watched
+                .iter()
+                .any(|(watched_feed, category)| *watched_feed == feed && category == VEHICLES_CATEGORY)
+            {
+                self.check(con, &feed, VEHICLES_CATEGORY)?;
+            }
+        }
+        for (feed, category) in watched {
+            self.check(con, &feed, &category)?;
+        }
+        Ok(())
+    }
+
+    fn check(&mut self, con: &mut Connection, feed: &str, category: &str) -> RedisResult<()> {
+        let vehicles = category == VEHICLES_CATEGORY;
+        if !vehicles && !self.updates.is_watched(feed, category) {
+            return Ok(());
+        }
+        let snapshot = match self.cache.load(con, feed, category) {
+            Ok(snapshot) => snapshot,
+            Err(e) if e.is_io_error() || e.is_connection_dropped() => return Err(e),
+            //nothing stored for it, or not anymore
+            Err(_) => {
+                if vehicles {
+                    self.all_vehicles.remove(feed);
+                }
+                return Ok(());
+            }
+        };
+        if vehicles {
+            self.all_vehicles.update(feed, snapshot.clone());
+        }
+        if !self.updates.is_watched(feed, category) {
+            return Ok(());
+        }
+        let key = format!("{}|{}", feed, category);
+        if self.last_times.get(&key) != Some(&snapshot.time) {
+            self.last_times.insert(key, snapshot.time);
+            self.updates.publish(feed, category, snapshot);
+        }
+        Ok(())
+    }
+}
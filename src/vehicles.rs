@@ -0,0 +1,299 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use gtfs_rt::VehiclePosition;
+use rstar::AABB;
+
+use crate::{
+    cache::Snapshot,
+    geo::{haversine, Area},
+};
+
+pub const VEHICLES_CATEGORY: &str = "vehicles";
+//feeds that haven't been inserted for this long are left out, so dead feeds don't leave ghosts on the map
+const MAX_FEED_AGE_MILLIS: u64 = 10 * 60 * 1000;
+
+//one vehicle found in one of the feeds
+pub struct FoundVehicle<'a> {
+    pub feed: &'a str,
+    pub entity_id: &'a str,
+    pub vehicle: &'a VehiclePosition,
+}
+
+//what the search endpoints return for each vehicle
+#[derive(Debug, Clone, Serialize)]
+pub struct VehicleMatch {
+    pub feed: String,
+    pub entity_id: String,
+    pub vehicle_id: Option<String>,
+    pub label: Option<String>,
+    pub trip_id: Option<String>,
+    pub route_id: Option<String>,
+    pub lat: Option<f32>,
+    pub lon: Option<f32>,
+    pub bearing: Option<f32>,
+    pub speed: Option<f32>,
+    pub timestamp: Option<u64>,
+    //meters from the point searched around
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f64>,
+}
+
+impl FoundVehicle<'_> {
+    pub fn to_match(&self, distance: Option<f64>) -> VehicleMatch {
+        let descriptor = self.vehicle.vehicle.as_ref();
+        let trip = self.vehicle.trip.as_ref();
+        let position = self.vehicle.position.as_ref();
+        VehicleMatch {
+            feed: self.feed.to_string(),
+            entity_id: self.entity_id.to_string(),
+            vehicle_id: descriptor.and_then(|d| d.id.clone()),
+            label: descriptor.and_then(|d| d.label.clone()),
+            trip_id: trip.and_then(|trip| trip.trip_id.clone()),
+            route_id: trip.and_then(|trip| trip.route_id.clone()),
+            lat: position.map(|position| position.latitude),
+            lon: position.map(|position| position.longitude),
+            bearing: position.and_then(|position| position.bearing),
+            speed: position.and_then(|position| position.speed),
+            timestamp: self.vehicle.timestamp,
+            distance,
+        }
+    }
+}
+
+//labels are matched ignoring case and surrounding spaces
+fn normalize_label(label: &str) -> String {
+    label.trim().to_lowercase()
+}
+
+//a feed's newest vehicles snapshot with its vehicles indexed by label
+pub struct IndexedFeed {
+    pub snapshot: Arc<Snapshot>,
+    labels: HashMap<String, Vec<usize>>,
+}
+
+impl IndexedFeed {
+    fn new(snapshot: Arc<Snapshot>) -> Option<IndexedFeed> {
+        //builds the spatial index now rather than on the first request
+        snapshot.vehicle_index()?;
+        let mut labels: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, entity) in snapshot.proto.as_ref().ok()?.entity.iter().enumerate() {
+            let label = entity
+                .vehicle
+                .as_ref()
+                .and_then(|vehicle| vehicle.vehicle.as_ref())
+                .and_then(|descriptor| descriptor.label.as_deref());
+            if let Some(label) = label {
+                labels.entry(normalize_label(label)).or_default().push(i);
+            }
+        }
+        Some(IndexedFeed { snapshot, labels })
+    }
+}
+
+//the newest vehicles snapshot of every feed, kept current by the update watcher
+//so map tiles and searches can span all feeds without reading redis
+//each snapshot brings its own spatial index, and gets a label index when it's added
+#[derive(Default)]
+pub struct AllVehicles {
+    feeds: RwLock<HashMap<String, Arc<IndexedFeed>>>,
+}
+
+impl AllVehicles {
+    pub fn new() -> AllVehicles {
+        AllVehicles::default()
+    }
+
+    pub fn update(&self, feed: &str, snapshot: Arc<Snapshot>) {
+        if let Some(current) = self.feeds.read().unwrap().get(feed) {
+            if current.snapshot.time >= snapshot.time {
+                return;
+            }
+        }
+        match IndexedFeed::new(snapshot) {
+            Some(indexed) => {
+                self.feeds
+                    .write()
+                    .unwrap()
+                    .insert(feed.to_string(), Arc::new(indexed));
+            }
+            None => self.remove(feed),
+        }
+    }
+
+    pub fn remove(&self, feed: &str) {
+        self.feeds.write().unwrap().remove(feed);
+    }
+
+    pub fn feeds(&self) -> Vec<String> {
+        self.feeds.read().unwrap().keys().cloned().collect()
+    }
+
+    //the feeds that are fresh enough to show and not hidden from the consumer
+    pub fn current(&self, hidden: &HashSet<String>, now_millis: u64) -> Vec<(String, Arc<IndexedFeed>)> {
+        self.feeds
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(feed, indexed)| {
+                !hidden.contains(*feed)
+                    && now_millis.saturating_sub(indexed.snapshot.time) <= MAX_FEED_AGE_MILLIS
+            })
+            .map(|(feed, indexed)| (feed.clone(), indexed.clone()))
+            .collect()
+    }
+}
+
+//every vehicle in the feeds whose position is inside a [lon, lat] rectangle
+pub fn in_envelope<'a>(
+    feeds: &'a [(String, Arc<IndexedFeed>)],
+    envelope: &AABB<[f64; 2]>,
+) -> Vec<FoundVehicle<'a>> {
+    let mut found = Vec::new();
+    for (feed, indexed) in feeds {
+        let snapshot = &indexed.snapshot;
+        let (proto, index) = match (snapshot.proto.as_ref(), snapshot.vehicle_index()) {
+            (Ok(proto), Some(index)) => (proto, index),
+            _ => continue,
+        };
+        for i in index.entities_in_envelope(envelope) {
+            let entity = &proto.entity[i];
+            if let Some(vehicle) = entity.vehicle.as_ref() {
+                found.push(FoundVehicle {
+                    feed,
+                    entity_id: &entity.id,
+                    vehicle,
+                });
+            }
+        }
+    }
+    found
+}
+
+//at most limit vehicles within meters of a point with their distance, closest first
+pub fn nearby<'a>(
+    feeds: &'a [(String, Arc<IndexedFeed>)],
+    lat: f64,
+    lon: f64,
+    meters: f64,
+    limit: usize,
+) -> Vec<(FoundVehicle<'a>, f64)> {
+    let area = Area::Radius { lat, lon, meters };
+    let mut found: Vec<(FoundVehicle, f64)> = area
+        .envelopes()
+        .iter()
+        .flat_map(|envelope| in_envelope(feeds, envelope))
+        .filter_map(|found| {
+            let position = found.vehicle.position.as_ref()?;
+            let distance = haversine(lat, lon, position.latitude as f64, position.longitude as f64);
+            (distance <= meters).then_some((found, distance))
+        })
+        .collect();
+    found.sort_by(|a, b| a.1.total_cmp(&b.1));
+    found.truncate(limit);
+    found
+}
+
+//vehicles whose label is the one given, ignoring case, in every feed
+pub fn with_label<'a>(feeds: &'a [(String, Arc<IndexedFeed>)], label: &str) -> Vec<FoundVehicle<'a>> {
+    let label = normalize_label(label);
+    let mut found = Vec::new();
+    for (feed, indexed) in feeds {
+        let (proto, entities) = match (indexed.snapshot.proto.as_ref(), indexed.labels.get(&label)) {
+            (Ok(proto), Some(entities)) => (proto, entities),
+            _ => continue,
+        };
+        for i in entities {
+            let entity = &proto.entity[*i];
+            if let Some(vehicle) = entity.vehicle.as_ref() {
+                found.push(FoundVehicle {
+                    feed,
+                    entity_id: &entity.id,
+                    vehicle,
+                });
+            }
+        }
+    }
+    found.sort_by(|a, b| a.feed.cmp(b.feed));
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use gtfs_rt::{FeedEntity, FeedMessage, Position};
+    use prost::Message;
+
+    use super::*;
+
+    //vehicles north of 0,0, about 111 meters apart per 0.001 degrees
+    fn feed(vehicles: &[(&str, f32)]) -> Arc<Snapshot> {
+        let mut message = FeedMessage::default();
+        for (id, lat) in vehicles {
+            let mut entity = FeedEntity::default();
+            entity.id = id.to_string();
+            entity.vehicle = Some(VehiclePosition {
+                position: Some(Position {
+                    latitude: *lat,
+                    longitude: 0.0,
+                    ..Position::default()
+                }),
+                ..VehiclePosition::default()
+            });
+            message.entity.push(entity);
+        }
+        Arc::new(Snapshot::new(1000, message.encode_to_vec()))
+    }
+
+    fn all_vehicles() -> AllVehicles {
+        let all_vehicles = AllVehicles::new();
+        all_vehicles.update("a", feed(&[("far", 0.004), ("near", 0.001), ("out", 0.02)]));
+        all_vehicles.update("b", feed(&[("middle", -0.002), ("closest", 0.0005)]));
+        all_vehicles
+    }
+
+    #[test]
+    fn nearby_is_closest_first_across_feeds() {
+        let all_vehicles = all_vehicles();
+        let feeds = all_vehicles.current(&HashSet::new(), 1000);
+        let found: Vec<(&str, &str)> = nearby(&feeds, 0.0, 0.0, 1000.0, 10)
+            .iter()
+            .map(|(found, _)| (found.feed, found.entity_id))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("b", "closest"),
+                ("a", "near"),
+                ("b", "middle"),
+                ("a", "far")
+            ]
+        );
+    }
+
+    #[test]
+    fn nearby_stops_at_the_radius_and_limit() {
+        let all_vehicles = all_vehicles();
+        let feeds = all_vehicles.current(&HashSet::new(), 1000);
+        let found = nearby(&feeds, 0.0, 0.0, 200.0, 10);
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|(_, distance)| *distance <= 200.0));
+
+        let found = nearby(&feeds, 0.0, 0.0, 1000.0, 3);
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[2].0.entity_id, "middle");
+    }
+
+    #[test]
+    fn hidden_and_old_feeds_are_left_out() {
+        let all_vehicles = all_vehicles();
+        let hidden = HashSet::from([String::from("b")]);
+        let feeds = all_vehicles.current(&hidden, 1000);
+        let found = nearby(&feeds, 0.0, 0.0, 1000.0, 10);
+        assert!(found.iter().all(|(found, _)| found.feed == "a"));
+        assert!(all_vehicles
+            .current(&HashSet::new(), 1000 + MAX_FEED_AGE_MILLIS + 1)
+            .is_empty());
+    }
+}
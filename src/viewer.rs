@@ -0,0 +1,469 @@
+use std::fmt::Write as _;
+
+use chrono::{TimeZone, Utc};
+use gtfs_rt::{
+    alert::{Cause, Effect},
+    feed_header::Incrementality,
+    trip_update::StopTimeEvent,
+    Alert, EntitySelector, FeedMessage, TranslatedString, TripUpdate, VehiclePosition,
+};
+
+use crate::{
+    catalog::{FeedStatus, CATEGORIES},
+    FailedPayload,
+};
+
+//clicking a column header sorts by data-sort when a cell has one, numerically when both values are numbers
+const SORT_SCRIPT: &str = r#"
+document.querySelectorAll("table.sortable > thead th").forEach((th, column) => {
+  th.addEventListener("click", () => {
+    const body = th.closest("table").tBodies[0];
+    const ascending = th.dataset.order !== "asc";
+    th.closest("tr").querySelectorAll("th").forEach((other) => delete other.dataset.order);
+    th.dataset.order = ascending ? "asc" : "desc";
+    const value = (row) => {
+      const cell = row.children[column];
+      return cell.dataset.sort !== undefined ? cell.dataset.sort : cell.textContent.trim();
+    };
+    const rows = Array.from(body.rows);
+    rows.sort((a, b) => {
+      const x = value(a), y = value(b);
+      const nx = parseFloat(x), ny = parseFloat(y);
+      const order = !isNaN(nx) && !isNaN(ny) ? nx - ny : x.localeCompare(y);
+      return ascending ? order : -order;
+    });
+    rows.forEach((row) => body.appendChild(row));
+  });
+});
+"#;
+
+const STYLE: &str = r#"
+body { font-family: system-ui, sans-serif; margin: 1.5em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 2em; font-size: 0.9em; }
+th, td { border: 1px solid #ccc; padding: 0.25em 0.5em; text-align: left; vertical-align: top; }
+table.sortable > thead th { cursor: pointer; background: #f0f0f0; }
+th[data-order="asc"]::after { content: " \25B2"; }
+th[data-order="desc"]::after { content: " \25BC"; }
+.late { color: #b00; }
+.early { color: #060; }
+.failing { color: #b00; font-weight: bold; }
+nav a { margin-right: 1em; }
+"#;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+//for values put into a query string, everything but unreserved characters is percent encoded
+fn encode_query(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
+
+fn opt(value: Option<&str>) -> String {
+    escape(value.unwrap_or(""))
+}
+
+//seconds since the epoch as a readable utc time
+fn time(seconds: i64) -> String {
+    match Utc.timestamp_opt(seconds, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => seconds.to_string(),
+    }
+}
+
+fn age(seconds: f64) -> String {
+    if seconds < 120.0 {
+        format!("{:.0}s", seconds)
+    } else if seconds < 7200.0 {
+        format!("{:.0}m {:.0}s", (seconds / 60.0).floor(), seconds % 60.0)
+    } else {
+        format!("{:.1}h", seconds / 3600.0)
+    }
+}
+
+fn delay(delay: i32) -> String {
+    let class = match delay {
+        d if d > 60 => "late",
+        d if d < -60 => "early",
+        _ => "",
+    };
+    format!("<span class=\"{}\">{:+}s</span>", class, delay)
+}
+
+fn stop_time_event(event: Option<&StopTimeEvent>) -> String {
+    let event = match event {
+        Some(event) => event,
+        None => return String::new(),
+    };
+    let mut parts: Vec<String> = Vec::new();
+    if let Some(value) = event.delay {
+        parts.push(delay(value));
+    }
+    if let Some(value) = event.time {
+        parts.push(time(value));
+    }
+    parts.join(" ")
+}
+
+//the english translation if there is one, otherwise the first
+fn translated(text: Option<&TranslatedString>) -> String {
+    let translations = match text {
+        Some(text) => &text.translation,
+        None => return String::new(),
+    };
+    translations
+        .iter()
+        .find(|translation| {
+            translation
+                .language
+                .as_deref()
+                .map_or(false, |language| language.starts_with("en"))
+        })
+        .or_else(|| translations.first())
+        .map(|translation| escape(&translation.text))
+        .unwrap_or_default()
+}
+
+fn selector(selector: &EntitySelector) -> String {
+    let trip = selector.trip.as_ref();
+    [
+        ("agency", selector.agency_id.as_deref()),
+        ("route", selector.route_id.as_deref()),
+        ("trip", trip.and_then(|trip| trip.trip_id.as_deref())),
+        ("stop", selector.stop_id.as_deref()),
+    ]
+    .iter()
+    .filter_map(|(name, value)| value.map(|value| format!("{} {}", name, escape(value))))
+    .collect::<Vec<String>>()
+    .join(", ")
+}
+
+//one server rendered page with everything in a snapshot, for people rather than programs
+pub struct ViewerPage<'a> {
+    pub feed: &'a str,
+    pub category: &'a str,
+    pub message: &'a FeedMessage,
+    //when the snapshot was inserted, in milliseconds
+    pub fetched: u64,
+    pub status: Option<&'a FeedStatus>,
+    //only given to admins, the error can include upstream urls and responses
+    pub failed: Option<&'a FailedPayload>,
+    pub now_millis: u64,
+}
+
+impl ViewerPage<'_> {
+    fn seconds_since(&self, seconds: u64) -> f64 {
+        (self.now_millis as f64 / 1000.0 - seconds as f64).max(0.0)
+    }
+
+    pub fn render(&self) -> String {
+        let mut html = String::new();
+        let title = format!("{} {}", escape(self.feed), escape(self.category));
+        let _ = write!(
+            html,
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head><body>",
+            title, STYLE
+        );
+        let _ = write!(html, "<h1>{}</h1><nav>", title);
+        for category in CATEGORIES {
+            let _ = write!(
+                html,
+                "<a href=\"?feed={}&amp;category={}\">{}</a>",
+                encode_query(self.feed),
+                category,
+                category
+            );
+        }
+        let _ = write!(
+            html,
+            "<a href=\"/gtfsrtasjson/?feed={}&amp;category={}\">json</a></nav>",
+            encode_query(self.feed),
+            encode_query(self.category)
+        );
+
+        self.render_status(&mut html);
+
+        let vehicles: Vec<(&str, &VehiclePosition)> = self
+            .message
+            .entity
+            .iter()
+            .filter_map(|entity| entity.vehicle.as_ref().map(|vehicle| (entity.id.as_str(), vehicle)))
+            .collect();
+        let trip_updates: Vec<(&str, &TripUpdate)> = self
+            .message
+            .entity
+            .iter()
+            .filter_map(|entity| {
+                entity
+                    .trip_update
+                    .as_ref()
+                    .map(|trip_update| (entity.id.as_str(), trip_update))
+            })
+            .collect();
+        let alerts: Vec<(&str, &Alert)> = self
+            .message
+            .entity
+            .iter()
+            .filter_map(|entity| entity.alert.as_ref().map(|alert| (entity.id.as_str(), alert)))
+            .collect();
+
+        if !vehicles.is_empty() {
+            self.render_vehicles(&mut html, &vehicles);
+        }
+        if !trip_updates.is_empty() {
+            self.render_trip_updates(&mut html, &trip_updates);
+        }
+        if !alerts.is_empty() {
+            self.render_alerts(&mut html, &alerts);
+        }
+        if vehicles.is_empty() && trip_updates.is_empty() && alerts.is_empty() {
+            html.push_str("<p>The feed has no entities.</p>");
+        }
+
+        let _ = writeln!(html, "<script>{}</script></body></html>", SORT_SCRIPT);
+        html
+    }
+
+    fn render_status(&self, html: &mut String) {
+        let header = &self.message.header;
+        let incrementality = match header.incrementality.and_then(Incrementality::from_i32) {
+            Some(Incrementality::Differential) => "differential",
+            _ => "full dataset",
+        };
+        let category = self.status.and_then(|status| status.category(self.category));
+
+        html.push_str("<h2>Status</h2><table>");
+        let mut row = |name: &str, value: String| {
+            let _ = write!(html, "<tr><th>{}</th><td>{}</td></tr>", name, value);
+        };
+        row("GTFS-rt version", escape(&header.gtfs_realtime_version));
+        row("Incrementality", String::from(incrementality));
+        if let Some(timestamp) = header.timestamp {
+            row(
+                "Header timestamp",
+                format!("{} ({} ago)", time(timestamp as i64), age(self.seconds_since(timestamp))),
+            );
+        }
+        row("Entities", self.message.entity.len().to_string());
+        row(
+            "Fetched",
+            format!(
+                "{} ({} ago)",
+                time((self.fetched / 1000) as i64),
+                age(self.now_millis.saturating_sub(self.fetched) as f64 / 1000.0)
+            ),
+        );
+        if let Some(changed) = category.and_then(|category| category.changed) {
+            row(
+                "Last changed",
+                format!(
+                    "{} ({} ago)",
+                    time((changed / 1000) as i64),
+                    age(self.now_millis.saturating_sub(changed) as f64 / 1000.0)
+                ),
+            );
+        }
+        if let Some(interval) = self.status.and_then(|status| status.fetch_interval) {
+            row("Fetch interval", format!("{}s", interval));
+        }
+        if let Some(category) = category {
+            row(
+                "Stale",
+                if category.stale { String::from("yes") } else { String::from("no") },
+            );
+        }
+        if let Some(failed) = self.failed {
+            //a failure older than the snapshot was already recovered from
+            let class = if failed.time > self.fetched { "failing" } else { "" };
+            row(
+                "Last failed fetch",
+                format!(
+                    "<span class=\"{}\">{}: {} {}</span>",
+                    class,
+                    time((failed.time / 1000) as i64),
+                    failed.status,
+                    escape(&failed.error)
+                ),
+            );
+        }
+        html.push_str("</table>");
+    }
+
+    fn render_vehicles(&self, html: &mut String, vehicles: &[(&str, &VehiclePosition)]) {
+        let _ = write!(
+            html,
+            "<h2>Vehicles ({})</h2><table class=\"sortable\"><thead><tr><th>id</th><th>label</th><th>trip</th><th>route</th><th>position</th><th>bearing</th><th>speed</th><th>stop</th><th>age</th></tr></thead><tbody>",
+            vehicles.len()
+        );
+        for (entity_id, vehicle) in vehicles {
+            let descriptor = vehicle.vehicle.as_ref();
+            let trip = vehicle.trip.as_ref();
+            let position = vehicle.position.as_ref();
+            let vehicle_age = vehicle
+                .timestamp
+                .map(|timestamp| self.seconds_since(timestamp));
+            let _ = write!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td data-sort=\"{}\">{}</td></tr>",
+                escape(descriptor.and_then(|d| d.id.as_deref()).unwrap_or(entity_id)),
+                opt(descriptor.and_then(|d| d.label.as_deref())),
+                opt(trip.and_then(|trip| trip.trip_id.as_deref())),
+                opt(trip.and_then(|trip| trip.route_id.as_deref())),
+                position
+                    .map(|position| format!("{:.5}, {:.5}", position.latitude, position.longitude))
+                    .unwrap_or_default(),
+                position
+                    .and_then(|position| position.bearing)
+                    .map(|bearing| format!("{:.0}", bearing))
+                    .unwrap_or_default(),
+                position
+                    .and_then(|position| position.speed)
+                    .map(|speed| format!("{:.1}", speed))
+                    .unwrap_or_default(),
+                opt(vehicle.stop_id.as_deref()),
+                vehicle_age.map(|seconds| seconds.to_string()).unwrap_or_default(),
+                vehicle_age.map(age).unwrap_or_default()
+            );
+        }
+        html.push_str("</tbody></table>");
+    }
+
+    fn render_trip_updates(&self, html: &mut String, trip_updates: &[(&str, &TripUpdate)]) {
+        let _ = write!(
+            html,
+            "<h2>Trip updates ({})</h2><table class=\"sortable\"><thead><tr><th>trip</th><th>route</th><th>vehicle</th><th>start</th><th>delay</th><th>stops</th><th>age</th></tr></thead><tbody>",
+            trip_updates.len()
+        );
+        for (entity_id, trip_update) in trip_updates {
+            let trip = &trip_update.trip;
+            //the trip's own delay, or the first stop's when it doesn't give one
+            let trip_delay = trip_update.delay.or_else(|| {
+                trip_update.stop_time_update.first().and_then(|stop_time| {
+                    stop_time
+                        .arrival
+                        .as_ref()
+                        .or(stop_time.departure.as_ref())
+                        .and_then(|event| event.delay)
+                })
+            });
+            let trip_age = trip_update
+                .timestamp
+                .map(|timestamp| self.seconds_since(timestamp));
+
+            let mut stops = String::from("<table><tr><th>seq</th><th>stop</th><th>arrival</th><th>departure</th></tr>");
+            for stop_time in trip_update.stop_time_update.iter() {
+                let _ = write!(
+                    stops,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    stop_time
+                        .stop_sequence
+                        .map(|sequence| sequence.to_string())
+                        .unwrap_or_default(),
+                    opt(stop_time.stop_id.as_deref()),
+                    stop_time_event(stop_time.arrival.as_ref()),
+                    stop_time_event(stop_time.departure.as_ref())
+                );
+            }
+            stops.push_str("</table>");
+
+            let _ = write!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td data-sort=\"{}\">{}</td><td data-sort=\"{}\"><details><summary>{} stops</summary>{}</details></td><td data-sort=\"{}\">{}</td></tr>",
+                escape(trip.trip_id.as_deref().unwrap_or(entity_id)),
+                opt(trip.route_id.as_deref()),
+                opt(trip_update.vehicle.as_ref().and_then(|v| v.label.as_deref().or(v.id.as_deref()))),
+                escape(
+                    &[trip.start_date.as_deref(), trip.start_time.as_deref()]
+                        .iter()
+                        .flatten()
+                        .cloned()
+                        .collect::<Vec<&str>>()
+                        .join(" ")
+                ),
+                trip_delay.map(|d| d.to_string()).unwrap_or_default(),
+                trip_delay.map(delay).unwrap_or_default(),
+                trip_update.stop_time_update.len(),
+                trip_update.stop_time_update.len(),
+                stops,
+                trip_age.map(|seconds| seconds.to_string()).unwrap_or_default(),
+                trip_age.map(age).unwrap_or_default()
+            );
+        }
+        html.push_str("</tbody></table>");
+    }
+
+    fn render_alerts(&self, html: &mut String, alerts: &[(&str, &Alert)]) {
+        let _ = write!(
+            html,
+            "<h2>Alerts ({})</h2><table class=\"sortable\"><thead><tr><th>id</th><th>header</th><th>effect</th><th>cause</th><th>active</th><th>informed entities</th></tr></thead><tbody>",
+            alerts.len()
+        );
+        for (entity_id, alert) in alerts {
+            let active = alert
+                .active_period
+                .iter()
+                .map(|period| {
+                    format!(
+                        "{} &ndash; {}",
+                        period.start.map(|start| time(start as i64)).unwrap_or_default(),
+                        period.end.map(|end| time(end as i64)).unwrap_or_default()
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("<br>");
+            let informed = alert
+                .informed_entity
+                .iter()
+                .map(selector)
+                .collect::<Vec<String>>()
+                .join("<br>");
+            let _ = write!(
+                html,
+                "<tr><td>{}</td><td><details><summary>{}</summary>{}</details></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(entity_id),
+                translated(alert.header_text.as_ref()),
+                translated(alert.description_text.as_ref()),
+                alert
+                    .effect
+                    .and_then(Effect::from_i32)
+                    .map(|effect| format!("{:?}", effect))
+                    .unwrap_or_default(),
+                alert
+                    .cause
+                    .and_then(Cause::from_i32)
+                    .map(|cause| format!("{:?}", cause))
+                    .unwrap_or_default(),
+                active,
+                informed
+            );
+        }
+        html.push_str("</tbody></table>");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_values_are_percent_encoded() {
+        assert_eq!(encode_query("f-metro~la"), "f-metro~la");
+        assert_eq!(encode_query("a&b=c#d e%"), "a%26b%3Dc%23d%20e%25");
+        assert_eq!(encode_query("\"><"), "%22%3E%3C");
+        assert_eq!(encode_query("é"), "%C3%A9");
+    }
+}
@@ -1,66 +1,313 @@
-use std::collections::HashSet;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use websocket::sync::{Server, receiver, sender};
-use websocket::message::OwnedMessage;
-
-fn handle_client(sender: sender, receiver: receiver, clients: Arc<Mutex<HashSet<sender>>>) {
-    // Add the client to the set
-    clients.lock().unwrap().insert(sender.clone());
-
-    for message in receiver.incoming_messages() {
-        match message {
-            Ok(OwnedMessage::Close(_)) => {
-                // Remove the client from the set and close the connection
-                clients.lock().unwrap().remove(&sender);
-                sender.send_message(&OwnedMessage::Close(None)).unwrap();
-                break;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use actix::{Actor, ActorContext, AsyncContext, SpawnHandle, StreamHandler};
+use actix_web_actors::ws;
+use futures::Stream;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    access::{AccessControl, ApiKey},
+    cache::{Snapshot, SnapshotCache},
+    error::FeedError,
+    updates::FeedUpdates,
+};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+//clients that don't answer pings for this long are disconnected
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+//every subscription holds a broadcast receiver, so one socket can't take unlimited
+pub const MAX_SUBSCRIPTIONS: usize = 64;
+
+//control messages sent by the client as json text frames
+//{"action":"subscribe","feed":"f-9q5-metro~losangeles~rail~rt","category":"vehicles"}
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum ControlMessage {
+    Subscribe { feed: String, category: String },
+    Unsubscribe { feed: String, category: String },
+}
+
+//events sent to the client as json text frames
+//every update is followed by a binary frame with the protobuf of the snapshot
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Event<'a> {
+    Subscribed {
+        feed: &'a str,
+        category: &'a str,
+    },
+    Unsubscribed {
+        feed: &'a str,
+        category: &'a str,
+    },
+    Update {
+        feed: &'a str,
+        category: &'a str,
+        time: u64,
+        hash: String,
+        size: usize,
+    },
+    //code is one of the codes of the http error bodies
+    Error {
+        code: &'a str,
+        message: String,
+    },
+}
+
+struct Subscription {
+    handle: SpawnHandle,
+    //time of the last snapshot sent, so nothing goes out twice
+    time: u64,
+}
+
+//a new snapshot for one of the socket's subscriptions
+pub struct FeedUpdate {
+    feed: String,
+    category: String,
+    snapshot: Arc<Snapshot>,
+}
+
+fn update_stream(
+    feed: String,
+    category: String,
+    receiver: broadcast::Receiver<Arc<Snapshot>>,
+) -> impl Stream<Item = FeedUpdate> {
+    futures::stream::unfold(receiver, move |mut receiver| {
+        let feed = feed.clone();
+        let category = category.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(snapshot) => {
+                        return Some((
+                            FeedUpdate {
+                                feed,
+                                category,
+                                snapshot,
+                            },
+                            receiver,
+                        ))
+                    }
+                    //the socket couldn't keep up, carry on from the newest snapshot
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
             }
-            Ok(_) => (),
-            Err(_) => {
-                // Handle errors here
+        }
+    })
+}
+
+//websocket that pushes new snapshots of every feed and category the client subscribed to
+//the actor is only polled while the connection can take more data,
+//so a slow client lags behind on the update channels and skips to the newest snapshot
+pub struct FeedSocket {
+    updates: Arc<FeedUpdates>,
+    cache: Arc<SnapshotCache>,
+    access: Arc<AccessControl>,
+    //key the socket was opened with, restricted feeds it doesn't list can't be subscribed to
+    api_key: Option<ApiKey>,
+    subscriptions: HashMap<String, Subscription>,
+    //opened on the first subscribe and reused, dropped after an error so the next one reconnects
+    con: Option<redis::Connection>,
+    //subscribed as soon as the socket opens, from ?feed=&category=
+    initial: Option<(String, String)>,
+    last_heartbeat: Instant,
+}
+
+impl FeedSocket {
+    pub fn new(
+        updates: Arc<FeedUpdates>,
+        cache: Arc<SnapshotCache>,
+        access: Arc<AccessControl>,
+        api_key: Option<ApiKey>,
+        initial: Option<(String, String)>,
+    ) -> FeedSocket {
+        FeedSocket {
+            updates,
+            cache,
+            access,
+            api_key,
+            subscriptions: HashMap::new(),
+            con: None,
+            initial,
+            last_heartbeat: Instant::now(),
+        }
+    }
+
+    fn send_event(&self, event: &Event, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.text(serde_json::to_string(event).unwrap());
+    }
+
+    fn send_error(&self, code: &str, message: String, ctx: &mut ws::WebsocketContext<Self>) {
+        self.send_event(&Event::Error { code, message }, ctx);
+    }
+
+    fn send_snapshot(
+        &mut self,
+        feed: &str,
+        category: &str,
+        snapshot: &Snapshot,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let key = format!("{}|{}", feed, category);
+        match self.subscriptions.get_mut(&key) {
+            Some(subscription) if subscription.time < snapshot.time => {
+                subscription.time = snapshot.time;
             }
+            _ => return,
         }
+        self.send_event(
+            &Event::Update {
+                feed,
+                category,
+                time: snapshot.time,
+                hash: snapshot.hash_string(),
+                size: snapshot.bytes.len(),
+            },
+            ctx,
+        );
+        ctx.binary(snapshot.bytes.clone());
     }
-}
 
-fn main() {
-    // Create a set to store connected clients
-    let clients = Arc::new(Mutex::new(HashSet::new()));
+    fn subscribe(&mut self, feed: String, category: String, ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.access.can_see(&feed, self.api_key.as_ref()) {
+            return self.send_error(
+                "restricted_feed",
+                format!("{} requires an authorised API key", feed),
+                ctx,
+            );
+        }
+        let key = format!("{}|{}", feed, category);
+        if self.subscriptions.contains_key(&key) {
+            return self.send_event(
+                &Event::Subscribed {
+                    feed: &feed,
+                    category: &category,
+                },
+                ctx,
+            );
+        }
 
-    // Start a WebSocket server
-    let server = Server::bind("127.0.0.1:8080").unwrap();
+        if self.subscriptions.len() >= MAX_SUBSCRIPTIONS {
+            return self.send_error(
+                "invalid_request",
+                format!("a socket can hold at most {} subscriptions", MAX_SUBSCRIPTIONS),
+                ctx,
+            );
+        }
 
-    for request in server.filter_map(Result::ok) {
-        if !request.protocols().contains(&"rust-websocket".to_string()) {
-            request.reject().unwrap();
-            continue;
+        let snapshot = match self.snapshot(&feed, &category) {
+            Ok(snapshot) => snapshot,
+            Err(e) => return self.send_error(e.code(), e.to_string(), ctx),
+        };
+
+        let receiver = self.updates.subscribe(&feed, &category);
+        let handle = ctx.add_stream(update_stream(feed.clone(), category.clone(), receiver));
+        self.subscriptions
+            .insert(key, Subscription { handle, time: 0 });
+        self.send_event(
+            &Event::Subscribed {
+                feed: &feed,
+                category: &category,
+            },
+            ctx,
+        );
+        self.send_snapshot(&feed, &category, &snapshot, ctx);
+    }
+
+    fn snapshot(&mut self, feed: &str, category: &str) -> Result<Arc<Snapshot>, FeedError> {
+        let con = match self.con.as_mut() {
+            Some(con) => con,
+            None => self.con.insert(
+                redis::Client::open("redis://127.0.0.1:6379/")
+                    .and_then(|redisclient| redisclient.get_connection())
+                    .map_err(FeedError::backend)?,
+            ),
+        };
+        let snapshot = self.cache.snapshot(con, feed, category);
+        if let Err(FeedError::BackendUnavailable { .. }) = snapshot {
+            self.con = None;
         }
+        snapshot
+    }
 
-        // Accept the WebSocket connection
-        let response = request.use_protocol("rust-websocket").accept().unwrap();
-        let (sender, receiver) = response.send().split().unwrap();
-        let clients_clone = clients.clone();
+    fn unsubscribe(&mut self, feed: String, category: String, ctx: &mut ws::WebsocketContext<Self>) {
+        let key = format!("{}|{}", feed, category);
+        if let Some(subscription) = self.subscriptions.remove(&key) {
+            ctx.cancel_future(subscription.handle);
+        }
+        self.send_event(
+            &Event::Unsubscribed {
+                feed: &feed,
+                category: &category,
+            },
+            ctx,
+        );
+    }
+}
+
+impl Actor for FeedSocket {
+    type Context = ws::WebsocketContext<Self>;
 
-        // Spawn a new thread to handle the WebSocket connection
-        thread::spawn(move || {
-            handle_client(sender, receiver, clients_clone);
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |socket, ctx| {
+            if Instant::now().duration_since(socket.last_heartbeat) > CLIENT_TIMEOUT {
+                return ctx.stop();
+            }
+            ctx.ping(b"");
         });
+        if let Some((feed, category)) = self.initial.take() {
+            self.subscribe(feed, category, ctx);
+        }
     }
+}
 
-    // Simulate data changes and send updates to clients
-    let data = Arc::new(Mutex::new("Initial Data".to_string()));
-    loop {
-        // Simulate a data change
-        let mut data = data.lock().unwrap();
-        *data = "Updated Data".to_string();
-
-        // Send updates to connected clients
-        let clients = clients.lock().unwrap();
-        for client in clients.iter() {
-            client.send_message(&OwnedMessage::Text(data.clone())).unwrap();
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for FeedSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(_) => return ctx.stop(),
+        };
+        self.last_heartbeat = Instant::now();
+        match msg {
+            ws::Message::Ping(bytes) => ctx.pong(&bytes),
+            ws::Message::Pong(_) => {}
+            ws::Message::Text(text) => match serde_json::from_str::<ControlMessage>(&text) {
+                Ok(ControlMessage::Subscribe { feed, category }) => {
+                    self.subscribe(feed, category, ctx)
+                }
+                Ok(ControlMessage::Unsubscribe { feed, category }) => {
+                    self.unsubscribe(feed, category, ctx)
+                }
+                Err(e) => self.send_error(
+                    "invalid_request",
+                    format!("invalid control message: {}", e),
+                    ctx,
+                ),
+            },
+            ws::Message::Binary(_) => self.send_error(
+                "invalid_request",
+                String::from("control messages must be json text"),
+                ctx,
+            ),
+            ws::Message::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            ws::Message::Continuation(_) | ws::Message::Nop => {}
         }
+    }
+}
 
-        thread::sleep(std::time::Duration::from_secs(5));
+impl StreamHandler<FeedUpdate> for FeedSocket {
+    fn handle(&mut self, update: FeedUpdate, ctx: &mut Self::Context) {
+        self.send_snapshot(&update.feed, &update.category, &update.snapshot, ctx);
     }
+
+    //a subscription ending isn't a reason to close the socket
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
 }